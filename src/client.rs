@@ -0,0 +1,317 @@
+//! Connection to the BuildKit Control API over a unix socket or TCP (optionally mutual TLS).
+//!
+//! A single channel is owned by a supervisor task and shared by the scrape loop
+//! and the build history subscription. When a caller sees the daemon go away it
+//! reports the failure and the supervisor reconnects with exponential backoff
+//! and jitter, so a restarting buildkitd is not hammered by reconnect attempts.
+
+use crate::generated::control_client::ControlClient;
+use crate::health::Health;
+use crate::metrics::{record_reconnect, record_rpc, record_rpc_error, record_up};
+
+use anyhow::{bail, Context, Result};
+use hyper_util::rt::TokioIo;
+use rand::Rng;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::UnixStream;
+use tokio::sync::{mpsc, watch};
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity, Uri};
+use tower::service_fn;
+
+const RECONNECT_DELAY_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(60);
+
+/// TLS material for `tcp://` daemons, mirroring buildctl's `--tlscacert`,
+/// `--tlscert`, `--tlskey` and `--tlsservername`.
+#[derive(Debug, Default)]
+pub struct TlsFiles {
+    pub ca_cert: Option<PathBuf>,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub server_name: Option<String>,
+}
+
+impl TlsFiles {
+    fn is_empty(&self) -> bool {
+        self.ca_cert.is_none()
+            && self.cert.is_none()
+            && self.key.is_none()
+            && self.server_name.is_none()
+    }
+
+    fn load(&self, host: &str) -> Result<ClientTlsConfig> {
+        let mut tls = ClientTlsConfig::new()
+            .domain_name(self.server_name.clone().unwrap_or_else(|| host.to_string()));
+        if let Some(ca) = &self.ca_cert {
+            let pem = std::fs::read(ca).with_context(|| format!("read {}", ca.display()))?;
+            tls = tls.ca_certificate(Certificate::from_pem(pem));
+        } else {
+            tls = tls.with_native_roots();
+        }
+        match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => {
+                let cert =
+                    std::fs::read(cert).with_context(|| format!("read {}", cert.display()))?;
+                let key = std::fs::read(key).with_context(|| format!("read {}", key.display()))?;
+                tls = tls.identity(Identity::from_pem(cert, key));
+            }
+            (None, None) => {}
+            _ => bail!("--tls-cert and --tls-key must be given together"),
+        }
+        Ok(tls)
+    }
+}
+
+/// Where and how to reach buildkitd.
+#[derive(Clone, Debug)]
+pub enum DaemonAddr {
+    Unix(PathBuf),
+    Tcp {
+        /// `host:port`
+        authority: String,
+        tls: Option<ClientTlsConfig>,
+    },
+}
+
+impl DaemonAddr {
+    /// Parse a buildctl-style address: `unix:///path`, a bare socket path, or
+    /// `tcp://host:port`. TLS options only apply to `tcp://` addresses.
+    pub fn parse(addr: &str, tls: &TlsFiles) -> Result<Self> {
+        if let Some(authority) = addr.strip_prefix("tcp://") {
+            let authority = authority.trim_end_matches('/').to_string();
+            let host = authority
+                .rsplit_once(':')
+                .map_or(authority.as_str(), |(host, _)| host)
+                .trim_start_matches('[')
+                .trim_end_matches(']');
+            let tls = if tls.is_empty() {
+                None
+            } else {
+                Some(tls.load(host)?)
+            };
+            return Ok(Self::Tcp { authority, tls });
+        }
+        if !tls.is_empty() {
+            bail!("TLS options require a tcp:// address, got {addr}");
+        }
+        if let Some((scheme, _)) = addr.split_once("://") {
+            if scheme != "unix" {
+                bail!("unsupported BuildKit address scheme {scheme}://");
+            }
+        }
+        Ok(Self::Unix(PathBuf::from(
+            addr.strip_prefix("unix://").unwrap_or(addr),
+        )))
+    }
+}
+
+impl std::fmt::Display for DaemonAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unix(path) => write!(f, "unix://{}", path.display()),
+            Self::Tcp { authority, .. } => write!(f, "tcp://{authority}"),
+        }
+    }
+}
+
+/// Handle to the supervised connection. Cheap to clone.
+#[derive(Clone)]
+pub struct Connection {
+    builder: Arc<str>,
+    health: Health,
+    client: watch::Receiver<Option<ControlClient<Channel>>>,
+    broken: mpsc::Sender<()>,
+}
+
+impl Connection {
+    /// Start the supervisor task for the daemon at `addr`, labelled `builder` in
+    /// metrics. Call outcomes are also recorded in `health`.
+    pub fn spawn(builder: String, addr: DaemonAddr, health: Health) -> Self {
+        let (tx, rx) = watch::channel(None);
+        let (broken, broken_rx) = mpsc::channel(1);
+        let this = Self {
+            builder: builder.as_str().into(),
+            health,
+            client: rx,
+            broken,
+        };
+        tokio::spawn(supervise(builder, addr, tx, broken_rx));
+        this
+    }
+
+    /// Await one Control API call, recording its duration and any error code
+    /// under `rpc` in the scrape self-observability metrics and health state.
+    pub async fn observe<T>(
+        &self,
+        rpc: &'static str,
+        call: impl Future<Output = Result<tonic::Response<T>, tonic::Status>>,
+    ) -> Result<T, tonic::Status> {
+        let start = Instant::now();
+        let result = call.await;
+        let code = result.as_ref().err().map(tonic::Status::code);
+        record_rpc(&self.builder, rpc, start.elapsed(), code);
+        self.health.record_rpc(rpc, result.as_ref().map(|_| ()));
+        result.map(tonic::Response::into_inner)
+    }
+
+    /// Record a streaming call that broke after it was opened.
+    pub fn stream_failed(&self, rpc: &'static str, status: &tonic::Status) {
+        record_rpc_error(&self.builder, rpc, status.code());
+        self.health.record_rpc(rpc, Err(status));
+    }
+
+    /// The current client, or an error if the daemon is not connected.
+    pub fn client(&self) -> Result<ControlClient<Channel>> {
+        self.client
+            .borrow()
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("not connected to buildkitd"))
+    }
+
+    /// Wait until a client is available.
+    pub async fn wait_client(&self) -> ControlClient<Channel> {
+        let mut rx = self.client.clone();
+        // The sender lives as long as the supervisor task, which never exits.
+        let client = rx
+            .wait_for(Option::is_some)
+            .await
+            .expect("connection supervisor stopped")
+            .clone();
+        client.unwrap()
+    }
+
+    /// Inspect an error from a Control API call and ask the supervisor to
+    /// reconnect if it indicates the transport is gone.
+    pub fn check(&self, err: &anyhow::Error) {
+        let transport_lost = err
+            .downcast_ref::<tonic::Status>()
+            .is_some_and(|s| s.code() == tonic::Code::Unavailable);
+        if transport_lost {
+            // Full means a reconnect is already pending.
+            let _ = self.broken.try_send(());
+        }
+    }
+}
+
+async fn supervise(
+    builder: String,
+    addr: DaemonAddr,
+    tx: watch::Sender<Option<ControlClient<Channel>>>,
+    mut broken: mpsc::Receiver<()>,
+) {
+    record_up(&builder, false);
+    let mut first = true;
+    loop {
+        let client = connect_with_backoff(&addr).await;
+        if !first {
+            record_reconnect(&builder);
+        }
+        first = false;
+        tracing::info!(builder, %addr, "connected to buildkitd");
+        // Drop failure reports about the previous channel that raced the reconnect.
+        while broken.try_recv().is_ok() {}
+        tx.send_replace(Some(client));
+
+        if broken.recv().await.is_none() {
+            return;
+        }
+        tracing::warn!(builder, "lost connection to buildkitd, reconnecting");
+        record_up(&builder, false);
+        tx.send_replace(None);
+    }
+}
+
+async fn connect_with_backoff(addr: &DaemonAddr) -> ControlClient<Channel> {
+    let mut delay = RECONNECT_DELAY_MIN;
+    loop {
+        match connect(addr).await {
+            Ok(client) => return client,
+            Err(e) => {
+                // Equal jitter: wait between half and all of the current delay.
+                let sleep = delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0));
+                tracing::warn!(err = %e, retry_in = ?sleep, "connect to buildkitd failed");
+                tokio::time::sleep(sleep).await;
+                delay = (delay * 2).min(RECONNECT_DELAY_MAX);
+            }
+        }
+    }
+}
+
+/// Open a channel to buildkitd at `addr` and wrap it in a Control client.
+async fn connect(addr: &DaemonAddr) -> Result<ControlClient<Channel>> {
+    let channel = match addr {
+        DaemonAddr::Unix(path) => {
+            let path = path.clone();
+            Endpoint::try_from("http://[::]:0")?
+                .connect_with_connector(service_fn(move |_: Uri| {
+                    let path = path.clone();
+                    async move {
+                        let stream = UnixStream::connect(path).await?;
+                        Ok::<_, std::io::Error>(TokioIo::new(stream))
+                    }
+                }))
+                .await?
+        }
+        DaemonAddr::Tcp { authority, tls } => {
+            let scheme = if tls.is_some() { "https" } else { "http" };
+            let mut endpoint = Endpoint::try_from(format!("{scheme}://{authority}"))?;
+            if let Some(tls) = tls {
+                endpoint = endpoint.tls_config(tls.clone())?;
+            }
+            endpoint.connect().await?
+        }
+    };
+
+    Ok(ControlClient::new(channel))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn parses_unix_addresses() {
+        let tls = TlsFiles::default();
+        let addr = DaemonAddr::parse("unix:///run/buildkit/buildkitd.sock", &tls).unwrap();
+        assert!(
+            matches!(addr, DaemonAddr::Unix(p) if p == Path::new("/run/buildkit/buildkitd.sock"))
+        );
+        let addr = DaemonAddr::parse("/tmp/bk.sock", &tls).unwrap();
+        assert!(matches!(addr, DaemonAddr::Unix(p) if p == Path::new("/tmp/bk.sock")));
+    }
+
+    #[test]
+    fn parses_tcp_addresses() {
+        let addr = DaemonAddr::parse("tcp://buildkitd:1234", &TlsFiles::default()).unwrap();
+        assert!(
+            matches!(addr, DaemonAddr::Tcp { authority, tls: None } if authority == "buildkitd:1234")
+        );
+    }
+
+    #[test]
+    fn rejects_tls_options_on_unix_sockets() {
+        let tls = TlsFiles {
+            server_name: Some("buildkitd".into()),
+            ..Default::default()
+        };
+        assert!(DaemonAddr::parse("unix:///run/buildkit/buildkitd.sock", &tls).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_schemes() {
+        assert!(DaemonAddr::parse("ssh://host", &TlsFiles::default()).is_err());
+    }
+
+    #[test]
+    fn requires_cert_and_key_together() {
+        let tls = TlsFiles {
+            cert: Some("/nonexistent/cert.pem".into()),
+            ..Default::default()
+        };
+        assert!(DaemonAddr::parse("tcp://buildkitd:1234", &tls).is_err());
+    }
+}
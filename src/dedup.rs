@@ -0,0 +1,267 @@
+//! Deduplication of completed build refs so build counters only move forward.
+//!
+//! BuildKit replays its retained history on every subscription, in ref order
+//! rather than completion order. Refs are remembered until BuildKit deletes
+//! them or they fall out of the history window; a watermark (latest
+//! `completed_at` evicted) covers refs dropped while BuildKit may still replay
+//! them. With a state file the watermark and refs survive restarts, so a
+//! restarted agent does not re-count history it already reported.
+
+use crate::generated::BuildHistoryRecord;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Hard cap on remembered refs; the oldest are evicted first.
+const MAX_REFS: usize = 10_000;
+
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+struct State {
+    /// Latest `completed_at` (unix seconds) of any evicted ref.
+    #[serde(default)]
+    evicted_seconds: Option<i64>,
+    /// Counted refs and their `completed_at` (unix seconds, if reported).
+    refs: HashMap<String, Option<i64>>,
+}
+
+pub struct SeenRefs {
+    state: State,
+    max_age: Duration,
+    state_file: Option<PathBuf>,
+    /// State changed since the last flush.
+    dirty: bool,
+}
+
+impl SeenRefs {
+    /// Create a store, loading previous state from `state_file` if it exists.
+    pub fn new(max_age: Duration, state_file: Option<PathBuf>) -> Self {
+        let state = match state_file.as_deref().map(load) {
+            Some(Ok(state)) => state,
+            Some(Err(e)) => {
+                tracing::warn!(err = %e, "ignoring unreadable dedup state file");
+                State::default()
+            }
+            None => State::default(),
+        };
+        Self {
+            state,
+            max_age,
+            state_file,
+            dirty: false,
+        }
+    }
+
+    /// Mark a completed record as seen. Returns true if it has not been counted yet.
+    pub fn insert(&mut self, record: &BuildHistoryRecord) -> bool {
+        let completed = record.completed_at.map(|t| t.seconds);
+        if self.state.refs.contains_key(&record.r#ref) {
+            return false;
+        }
+        // Eviction drops the oldest refs first, so anything not newer than the
+        // latest evicted one was counted before. Deleted refs are never replayed
+        // and do not move the watermark.
+        if let (Some(c), Some(w)) = (completed, self.state.evicted_seconds) {
+            if c <= w {
+                return false;
+            }
+        }
+
+        self.state.refs.insert(record.r#ref.clone(), completed);
+        self.evict(SystemTime::now());
+        self.dirty = true;
+        true
+    }
+
+    /// Forget a ref BuildKit has deleted from its history.
+    pub fn forget(&mut self, r#ref: &str) {
+        if self.state.refs.remove(r#ref).is_some() {
+            self.dirty = true;
+        }
+    }
+
+    /// Drop refs older than the history window, then the oldest beyond
+    /// `MAX_REFS`, raising the watermark to cover them. Refs without a
+    /// completion time cannot be covered, so they stay until deleted.
+    fn evict(&mut self, now: SystemTime) {
+        let cutoff = now
+            .checked_sub(self.max_age)
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs() as i64);
+        let mut evicted = self.state.evicted_seconds;
+        self.state.refs.retain(|_, completed| match *completed {
+            Some(c) if c < cutoff => {
+                evicted = evicted.max(Some(c));
+                false
+            }
+            _ => true,
+        });
+
+        if self.state.refs.len() > MAX_REFS {
+            let mut by_age: Vec<_> = self
+                .state
+                .refs
+                .iter()
+                .filter_map(|(r, c)| Some(((*c)?, r.clone())))
+                .collect();
+            by_age.sort_unstable();
+            let excess = self.state.refs.len() - MAX_REFS;
+            for (c, r) in by_age.into_iter().take(excess) {
+                self.state.refs.remove(&r);
+                evicted = evicted.max(Some(c));
+            }
+        }
+        self.state.evicted_seconds = evicted;
+    }
+
+    /// Write the state file if anything changed since the last flush. Called
+    /// periodically by the history task, off the async runtime.
+    pub async fn flush(&mut self) {
+        let Some(path) = &self.state_file else { return };
+        if !self.dirty {
+            return;
+        }
+        let bytes = match serde_json::to_vec(&self.state) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                tracing::warn!(err = %e, "failed to encode dedup state");
+                return;
+            }
+        };
+        self.dirty = false;
+        let target = path.clone();
+        let result = tokio::task::spawn_blocking(move || save(&target, &bytes)).await;
+        if let Err(e) = result.map_err(anyhow::Error::from).and_then(|r| r) {
+            self.dirty = true;
+            tracing::warn!(err = %e, path = %path.display(), "failed to write dedup state file");
+        }
+    }
+}
+
+fn load(path: &Path) -> Result<State> {
+    match std::fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes).context("parse dedup state"),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(State::default()),
+        Err(e) => Err(e).context("read dedup state"),
+    }
+}
+
+/// Write via a temp file and rename so a crash never leaves a truncated file.
+fn save(path: &Path, bytes: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, bytes)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(86_400);
+
+    fn completed(r#ref: &str, seconds: i64) -> BuildHistoryRecord {
+        BuildHistoryRecord {
+            r#ref: r#ref.into(),
+            completed_at: Some(prost_types::Timestamp { seconds, nanos: 0 }),
+            ..Default::default()
+        }
+    }
+
+    fn now_seconds() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+    }
+
+    #[test]
+    fn counts_each_ref_once() {
+        let mut seen = SeenRefs::new(DAY, None);
+        let t = now_seconds();
+        assert!(seen.insert(&completed("a", t)));
+        assert!(!seen.insert(&completed("a", t)));
+        assert!(seen.insert(&completed("b", t)));
+    }
+
+    #[test]
+    fn older_refs_replayed_later_are_counted() {
+        let mut seen = SeenRefs::new(DAY, None);
+        let t = now_seconds();
+        assert!(seen.insert(&completed("b", t)));
+        assert!(seen.insert(&completed("a", t - 10)));
+        assert!(!seen.insert(&completed("a", t - 10)));
+    }
+
+    #[test]
+    fn watermark_skips_evicted_refs() {
+        let mut seen = SeenRefs::new(DAY, None);
+        let t = now_seconds();
+        let old = t - 2 * DAY.as_secs() as i64;
+        assert!(seen.insert(&completed("old", old)));
+        assert!(!seen.state.refs.contains_key("old"));
+        assert!(!seen.insert(&completed("old", old)));
+        assert!(seen.insert(&completed("new", t)));
+    }
+
+    #[test]
+    fn deleted_refs_do_not_raise_watermark() {
+        let mut seen = SeenRefs::new(DAY, None);
+        let t = now_seconds();
+        assert!(seen.insert(&completed("b", t)));
+        seen.forget("b");
+        assert!(seen.insert(&completed("a", t - 10)));
+    }
+
+    #[test]
+    fn refs_without_completion_time_are_kept() {
+        let mut seen = SeenRefs::new(DAY, None);
+        let record = BuildHistoryRecord {
+            r#ref: "a".into(),
+            ..Default::default()
+        };
+        assert!(seen.insert(&record));
+        assert!(!seen.insert(&record));
+        assert_eq!(seen.state.evicted_seconds, None);
+    }
+
+    #[test]
+    fn equal_timestamps_are_not_dropped() {
+        let mut seen = SeenRefs::new(DAY, None);
+        let t = now_seconds();
+        assert!(seen.insert(&completed("a", t)));
+        assert!(seen.insert(&completed("b", t)));
+    }
+
+    #[test]
+    fn refs_outside_window_are_evicted() {
+        let mut seen = SeenRefs::new(DAY, None);
+        let t = now_seconds();
+        seen.insert(&completed("old", t - 2 * DAY.as_secs() as i64));
+        seen.insert(&completed("new", t));
+        assert!(!seen.state.refs.contains_key("old"));
+        assert!(seen.state.refs.contains_key("new"));
+    }
+
+    #[tokio::test]
+    async fn state_survives_restart() {
+        let path = std::env::temp_dir().join(format!(
+            "buildkit-metrics-agent-dedup-{}.json",
+            std::process::id()
+        ));
+        let t = now_seconds();
+        {
+            let mut seen = SeenRefs::new(DAY, Some(path.clone()));
+            assert!(seen.insert(&completed("a", t - 10)));
+            assert!(seen.insert(&completed("b", t)));
+            seen.flush().await;
+        }
+        let mut seen = SeenRefs::new(DAY, Some(path.clone()));
+        assert!(!seen.insert(&completed("a", t - 10)));
+        assert!(!seen.insert(&completed("b", t)));
+        assert!(seen.insert(&completed("c", t + 1)));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
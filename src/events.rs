@@ -0,0 +1,369 @@
+//! Build event log: one JSON line per newly counted build, for log pipelines
+//! (Loki, Vector) that want per-build facts rather than aggregates.
+//!
+//! Events go to stdout, a local file rotated by size, or a unix datagram
+//! socket. Writes are best effort: a writer thread drains a
+//! [`SinkQueue`](crate::history::SinkQueue) and logs failures.
+
+use crate::generated::BuildHistoryRecord;
+use crate::history::SinkQueue;
+use crate::labels::BuildLabels;
+use crate::metrics::is_canceled;
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Where events are written, parsed from `stdout`, `file:<path>` or
+/// `unixgram:<path>`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Output {
+    Stdout,
+    File(PathBuf),
+    Unixgram(PathBuf),
+}
+
+impl Output {
+    pub fn parse(spec: &str) -> Result<Self> {
+        if spec == "stdout" {
+            return Ok(Self::Stdout);
+        }
+        match spec.split_once(':') {
+            Some(("file", path)) if !path.is_empty() => Ok(Self::File(path.into())),
+            Some(("unixgram", path)) if !path.is_empty() => {
+                Ok(Self::Unixgram(path.trim_start_matches("//").into()))
+            }
+            _ => bail!("build event log {spec:?} must be stdout, file:<path> or unixgram:<path>"),
+        }
+    }
+}
+
+/// One completed build as written to the log.
+#[derive(Debug, Serialize)]
+struct BuildEvent<'a> {
+    builder: &'a str,
+    #[serde(rename = "ref")]
+    r#ref: &'a str,
+    frontend: &'a str,
+    /// `succeeded`, `failed` or `canceled`.
+    status: &'static str,
+    created_at_seconds: Option<f64>,
+    completed_at_seconds: Option<f64>,
+    duration_seconds: Option<f64>,
+    cached_steps: i32,
+    total_steps: i32,
+    completed_steps: i32,
+    error: Option<EventError<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct EventError<'a> {
+    code: i32,
+    message: &'a str,
+    /// Failure reason as on `buildkit_builds_failed_total`; absent when canceled.
+    reason: Option<String>,
+}
+
+impl<'a> BuildEvent<'a> {
+    fn new(builder: &'a str, build_labels: &BuildLabels, record: &'a BuildHistoryRecord) -> Self {
+        let at = |t: Option<prost_types::Timestamp>| SystemTime::try_from(t?).ok();
+        let seconds = |t: SystemTime| {
+            t.duration_since(SystemTime::UNIX_EPOCH)
+                .ok()
+                .map(|d| d.as_secs_f64())
+        };
+        let created = at(record.created_at);
+        let completed = at(record.completed_at);
+        let duration = match (created, completed) {
+            (Some(created), Some(completed)) => completed.duration_since(created).ok(),
+            _ => None,
+        };
+        let error = record.error.as_ref().filter(|e| e.code != 0);
+        let canceled = error.is_some_and(is_canceled);
+        let status = match error {
+            None => "succeeded",
+            Some(_) if canceled => "canceled",
+            Some(_) => "failed",
+        };
+        Self {
+            builder,
+            r#ref: &record.r#ref,
+            frontend: &record.frontend,
+            status,
+            created_at_seconds: created.and_then(seconds),
+            completed_at_seconds: completed.and_then(seconds),
+            duration_seconds: duration.map(|d| d.as_secs_f64()),
+            cached_steps: record.num_cached_steps,
+            total_steps: record.num_total_steps,
+            completed_steps: record.num_completed_steps,
+            error: error.map(|e| EventError {
+                code: e.code,
+                message: &e.message,
+                reason: (!canceled).then(|| build_labels.failure_reason(e)),
+            }),
+        }
+    }
+}
+
+enum Sink {
+    Stdout,
+    File(RotatingFile),
+    Unixgram(UnixDatagram, PathBuf),
+}
+
+impl Sink {
+    fn open(output: &Output, max_bytes: u64, keep: u32) -> Result<Self> {
+        Ok(match output {
+            Output::Stdout => Self::Stdout,
+            Output::File(path) => Self::File(RotatingFile::open(path, max_bytes, keep)?),
+            Output::Unixgram(path) => {
+                let socket = UnixDatagram::unbound()?;
+                // A stalled reader drops events instead of blocking the writer.
+                socket.set_nonblocking(true)?;
+                Self::Unixgram(socket, path.clone())
+            }
+        })
+    }
+
+    fn write(&mut self, line: &[u8]) -> Result<()> {
+        match self {
+            Self::Stdout => std::io::stdout().lock().write_all(line).map_err(Into::into),
+            Self::File(file) => file.write(line),
+            Self::Unixgram(socket, path) => socket
+                .send_to(line, &*path)
+                .map(drop)
+                .with_context(|| format!("send to {}", path.display())),
+        }
+    }
+}
+
+/// Appends to `path`, moving it to `path.1` (and older files up to
+/// `path.<keep>`) once the next line would take it past `max_bytes`.
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: u32,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_bytes: u64, keep: u32) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("open {}", path.display()))?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_bytes,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    fn write(&mut self, line: &[u8]) -> Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        let rotated = |n: u32| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{n}"));
+            PathBuf::from(name)
+        };
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                match std::fs::rename(rotated(n), rotated(n + 1)) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+            }
+            std::fs::rename(&self.path, rotated(1))?;
+        }
+        *self = Self::open(&self.path, self.max_bytes, self.keep)?;
+        Ok(())
+    }
+}
+
+/// Queues build events for a writer thread. Cheap to clone.
+#[derive(Clone)]
+pub struct EventLog {
+    queue: SinkQueue<Vec<u8>>,
+}
+
+impl EventLog {
+    /// Open the sink and start its writer. `max_bytes` and `keep` only apply
+    /// to file output.
+    pub fn open(output: &Output, max_bytes: u64, keep: u32) -> Result<Self> {
+        let mut sink = Sink::open(output, max_bytes, keep)?;
+        let (queue, mut rx) = SinkQueue::<Vec<u8>>::new();
+        tokio::task::spawn_blocking(move || {
+            while let Some(line) = rx.blocking_recv() {
+                if let Err(e) = sink.write(&line) {
+                    tracing::warn!(err = %e, "failed to write build event");
+                }
+            }
+        });
+        Ok(Self { queue })
+    }
+
+    /// Queue the event of one newly counted build.
+    pub fn record(&self, builder: &str, build_labels: &BuildLabels, record: &BuildHistoryRecord) {
+        let event = BuildEvent::new(builder, build_labels, record);
+        let mut line = match serde_json::to_vec(&event) {
+            Ok(line) => line,
+            Err(e) => {
+                tracing::warn!(err = %e, "failed to encode build event");
+                return;
+            }
+        };
+        line.push(b'\n');
+        self.queue.send("events", builder, &record.r#ref, line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generated::BuildError;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "buildkit-metrics-agent-{name}-{}",
+            std::process::id()
+        ))
+    }
+
+    fn record(r#ref: &str, error: Option<(i32, &str)>) -> BuildHistoryRecord {
+        BuildHistoryRecord {
+            r#ref: r#ref.into(),
+            frontend: "dockerfile.v0".into(),
+            created_at: Some(prost_types::Timestamp {
+                seconds: 1_700_000_000,
+                nanos: 0,
+            }),
+            completed_at: Some(prost_types::Timestamp {
+                seconds: 1_700_000_042,
+                nanos: 500_000_000,
+            }),
+            error: error.map(|(code, message)| BuildError {
+                code,
+                message: message.into(),
+            }),
+            num_cached_steps: 3,
+            num_total_steps: 5,
+            num_completed_steps: 5,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parses_outputs() {
+        assert_eq!(Output::parse("stdout").unwrap(), Output::Stdout);
+        assert_eq!(
+            Output::parse("file:/var/log/builds.jsonl").unwrap(),
+            Output::File("/var/log/builds.jsonl".into())
+        );
+        assert_eq!(
+            Output::parse("unixgram:///run/vector.sock").unwrap(),
+            Output::Unixgram("/run/vector.sock".into())
+        );
+        assert!(Output::parse("stderr").is_err());
+        assert!(Output::parse("file:").is_err());
+    }
+
+    #[test]
+    fn failed_build_event() {
+        let failed = record(
+            "b1",
+            Some((
+                2,
+                "process \"/bin/sh -c make\" did not complete successfully: exit code: 2",
+            )),
+        );
+        let event = BuildEvent::new("pool-0", &BuildLabels::default(), &failed);
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["builder"], "pool-0");
+        assert_eq!(json["ref"], "b1");
+        assert_eq!(json["status"], "failed");
+        assert_eq!(json["duration_seconds"], 42.5);
+        assert_eq!(json["cached_steps"], 3);
+        assert_eq!(json["error"]["code"], 2);
+        assert_eq!(json["error"]["reason"], "step_failed");
+
+        let canceled = record("b2", Some((1, "context canceled")));
+        let json = serde_json::to_value(BuildEvent::new(
+            "pool-0",
+            &BuildLabels::default(),
+            &canceled,
+        ))
+        .unwrap();
+        assert_eq!(json["status"], "canceled");
+        assert!(json["error"]["reason"].is_null());
+    }
+
+    fn line(r#ref: &str) -> Vec<u8> {
+        let labels = BuildLabels::default();
+        let mut line =
+            serde_json::to_vec(&BuildEvent::new("pool-0", &labels, &record(r#ref, None))).unwrap();
+        line.push(b'\n');
+        line
+    }
+
+    #[test]
+    fn file_output_rotates_by_size() {
+        let path = temp_path("events.jsonl");
+        let rotated = |n| PathBuf::from(format!("{}.{n}", path.display()));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(rotated(1));
+        let _ = std::fs::remove_file(rotated(2));
+
+        // Exactly two lines fit, so the third starts a new file.
+        let size = line("a").len() as u64;
+        let mut sink = Sink::open(&Output::File(path.clone()), 2 * size, 2).unwrap();
+        for r in ["a", "b", "c", "d", "e"] {
+            sink.write(&line(r)).unwrap();
+        }
+        let read = |path: &Path| std::fs::read(path).unwrap();
+        assert_eq!(read(&path), line("e"));
+        assert_eq!(read(&rotated(1)), [line("c"), line("d")].concat());
+        assert_eq!(read(&rotated(2)), [line("a"), line("b")].concat());
+        assert!(!rotated(3).exists());
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(rotated(1)).unwrap();
+        std::fs::remove_file(rotated(2)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unixgram_output_sends_one_datagram_per_build() {
+        let path = temp_path("events.sock");
+        let _ = std::fs::remove_file(&path);
+        let reader = UnixDatagram::bind(&path).unwrap();
+        reader
+            .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        let log = EventLog::open(&Output::Unixgram(path.clone()), 0, 0).unwrap();
+        log.record("pool-0", &BuildLabels::default(), &record("b1", None));
+
+        let mut buf = [0; 4096];
+        let n = reader.recv(&mut buf).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&buf[..n]).unwrap();
+        assert_eq!(json["ref"], "b1");
+        assert_eq!(json["status"], "succeeded");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
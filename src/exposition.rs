@@ -0,0 +1,242 @@
+//! Protobuf exposition format for `/metrics`, the only format that carries
+//! native (sparse) histograms. Served from the [`Store`](crate::store::Store)
+//! to scrapers that ask for it; everyone else keeps getting text.
+
+use crate::generated::client_model::{
+    Bucket, BucketSpan, Counter, Gauge, Histogram, LabelPair, Metric, MetricFamily, MetricType,
+};
+use crate::store::{Sample, Sparse, Value, NATIVE_ZERO_THRESHOLD};
+
+use prost::Message;
+use std::collections::BTreeMap;
+
+/// Content type of the length-delimited `MetricFamily` stream.
+pub const CONTENT_TYPE: &str =
+    "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited";
+
+/// Whether an `Accept` header asks for the delimited protobuf format, as
+/// Prometheus does with native histograms enabled.
+pub fn accepts_protobuf(accept: &str) -> bool {
+    accept.split(',').any(|media| {
+        let mut params = media.split(';').map(str::trim);
+        params.next() == Some("application/vnd.google.protobuf")
+            && params.any(|p| p == "proto=io.prometheus.client.MetricFamily")
+    })
+}
+
+/// Encode `samples` (sorted by name, as from a snapshot) as one
+/// length-delimited `MetricFamily` per metric name.
+pub fn encode(samples: &[Sample]) -> Vec<u8> {
+    let mut families: Vec<MetricFamily> = Vec::new();
+    for sample in samples {
+        let kind = match sample.value {
+            Value::Counter(_) => MetricType::Counter,
+            Value::Gauge(_) => MetricType::Gauge,
+            Value::Histogram { .. } => MetricType::Histogram,
+        };
+        if families.last().is_none_or(|f| f.name != sample.name) {
+            families.push(MetricFamily {
+                name: sample.name.clone(),
+                r#type: kind as i32,
+                ..Default::default()
+            });
+        }
+        let family = families.last_mut().expect("pushed above");
+        // One name recorded as two kinds; keep the first.
+        if family.r#type != kind as i32 {
+            continue;
+        }
+        family.metric.push(metric(sample));
+    }
+    let mut buf = Vec::new();
+    for family in families {
+        family
+            .encode_length_delimited(&mut buf)
+            .expect("Vec grows as needed");
+    }
+    buf
+}
+
+fn metric(sample: &Sample) -> Metric {
+    let label = sample
+        .labels
+        .iter()
+        .map(|(name, value)| LabelPair {
+            name: name.clone(),
+            value: value.clone(),
+        })
+        .collect();
+    let mut metric = Metric {
+        label,
+        ..Default::default()
+    };
+    match &sample.value {
+        &Value::Counter(value) => {
+            metric.counter = Some(Counter {
+                value: value as f64,
+            })
+        }
+        &Value::Gauge(value) => metric.gauge = Some(Gauge { value }),
+        Value::Histogram {
+            bounds,
+            counts,
+            sum,
+            count,
+            native,
+        } => {
+            let mut cumulative = 0;
+            let bucket = bounds
+                .iter()
+                .zip(counts)
+                .map(|(&upper_bound, &n)| {
+                    cumulative += n;
+                    Bucket {
+                        cumulative_count: cumulative,
+                        upper_bound,
+                    }
+                })
+                .collect();
+            let mut histogram = Histogram {
+                sample_count: *count,
+                sample_sum: *sum,
+                bucket,
+                ..Default::default()
+            };
+            if let Some(native) = native {
+                set_native(&mut histogram, native);
+            }
+            metric.histogram = Some(histogram);
+        }
+    }
+    metric
+}
+
+fn set_native(histogram: &mut Histogram, native: &Sparse) {
+    histogram.schema = native.schema;
+    histogram.zero_threshold = NATIVE_ZERO_THRESHOLD;
+    histogram.zero_count = native.zero_count;
+    (histogram.positive_span, histogram.positive_delta) = spans(&native.positive);
+    (histogram.negative_span, histogram.negative_delta) = spans(&native.negative);
+    if histogram.positive_span.is_empty() && histogram.negative_span.is_empty() {
+        // An empty span marks the histogram as native before any observation.
+        histogram.positive_span.push(BucketSpan {
+            offset: 0,
+            length: 0,
+        });
+    }
+}
+
+/// Spans of consecutive populated buckets, and each bucket's count as the
+/// delta to the previous one.
+fn spans(buckets: &BTreeMap<i32, u64>) -> (Vec<BucketSpan>, Vec<i64>) {
+    let mut spans: Vec<BucketSpan> = Vec::new();
+    let mut deltas = Vec::with_capacity(buckets.len());
+    let mut next_index = None;
+    let mut previous = 0i64;
+    for (&index, &count) in buckets {
+        match (next_index, spans.last_mut()) {
+            (Some(next), Some(span)) if next == index => span.length += 1,
+            (Some(next), _) => spans.push(BucketSpan {
+                offset: index - next,
+                length: 1,
+            }),
+            (None, _) => spans.push(BucketSpan {
+                offset: index,
+                length: 1,
+            }),
+        }
+        deltas.push(count as i64 - previous);
+        previous = count as i64;
+        next_index = Some(index + 1);
+    }
+    (spans, deltas)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_prometheus_protobuf_negotiation() {
+        assert!(accepts_protobuf(
+            "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=delimited;q=0.7,text/plain;version=0.0.4;q=0.3"
+        ));
+        assert!(!accepts_protobuf("text/plain;version=0.0.4"));
+        assert!(!accepts_protobuf(
+            "application/openmetrics-text; version=1.0.0"
+        ));
+    }
+
+    #[test]
+    fn spans_split_on_gaps_with_deltas() {
+        let buckets = BTreeMap::from([(-2, 1), (-1, 3), (2, 2), (3, 2)]);
+        let (spans, deltas) = spans(&buckets);
+        assert_eq!(
+            spans,
+            [
+                BucketSpan {
+                    offset: -2,
+                    length: 2
+                },
+                BucketSpan {
+                    offset: 2,
+                    length: 2
+                }
+            ]
+        );
+        assert_eq!(deltas, [1, 2, -1, 0]);
+    }
+
+    #[test]
+    fn encodes_one_family_per_name() {
+        let sample = |name: &str, builder: &str, value| Sample {
+            name: name.into(),
+            labels: vec![("builder".into(), builder.into())],
+            value,
+        };
+        let native = Sparse {
+            schema: 3,
+            zero_count: 0,
+            positive: BTreeMap::from([(8, 2)]),
+            negative: BTreeMap::new(),
+        };
+        let samples = [
+            sample(
+                "buildkit_build_steps",
+                "a",
+                Value::Histogram {
+                    bounds: vec![1.0, 5.0],
+                    counts: vec![0, 2, 0],
+                    sum: 4.0,
+                    count: 2,
+                    native: Some(native),
+                },
+            ),
+            sample("buildkit_builds_total", "a", Value::Counter(3)),
+            sample("buildkit_builds_total", "b", Value::Counter(1)),
+        ];
+        let mut buf = &encode(&samples)[..];
+        let steps = MetricFamily::decode_length_delimited(&mut buf).unwrap();
+        let builds = MetricFamily::decode_length_delimited(&mut buf).unwrap();
+        assert!(buf.is_empty());
+
+        assert_eq!(steps.r#type, MetricType::Histogram as i32);
+        let histogram = steps.metric[0].histogram.as_ref().unwrap();
+        assert_eq!((histogram.sample_count, histogram.sample_sum), (2, 4.0));
+        assert_eq!(histogram.bucket[1].cumulative_count, 2);
+        assert_eq!(histogram.schema, 3);
+        assert_eq!(
+            histogram.positive_span,
+            [BucketSpan {
+                offset: 8,
+                length: 1
+            }]
+        );
+        assert_eq!(histogram.positive_delta, [2]);
+
+        assert_eq!(builds.r#type, MetricType::Counter as i32);
+        assert_eq!(builds.metric.len(), 2);
+        assert_eq!(builds.metric[1].label[0].value, "b");
+        assert_eq!(builds.metric[1].counter.unwrap().value, 1.0);
+    }
+}
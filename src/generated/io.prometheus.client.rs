@@ -0,0 +1,121 @@
+// This file is @generated by prost-build.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct LabelPair {
+    #[prost(string, tag = "1")]
+    pub name: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub value: ::prost::alloc::string::String,
+}
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct Gauge {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+}
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct Counter {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Histogram {
+    #[prost(uint64, tag = "1")]
+    pub sample_count: u64,
+    #[prost(double, tag = "2")]
+    pub sample_sum: f64,
+    /// Classic buckets, ordered by upper bound, without the +Inf bucket.
+    #[prost(message, repeated, tag = "3")]
+    pub bucket: ::prost::alloc::vec::Vec<Bucket>,
+    /// Native histogram: bucket `i` counts observations in
+    /// (base^(i-1), base^i\], base = 2^(2^-schema).
+    #[prost(sint32, tag = "5")]
+    pub schema: i32,
+    #[prost(double, tag = "6")]
+    pub zero_threshold: f64,
+    #[prost(uint64, tag = "7")]
+    pub zero_count: u64,
+    #[prost(message, repeated, tag = "9")]
+    pub negative_span: ::prost::alloc::vec::Vec<BucketSpan>,
+    /// Count of each bucket as the delta to the previous bucket.
+    #[prost(sint64, repeated, tag = "10")]
+    pub negative_delta: ::prost::alloc::vec::Vec<i64>,
+    #[prost(message, repeated, tag = "12")]
+    pub positive_span: ::prost::alloc::vec::Vec<BucketSpan>,
+    #[prost(sint64, repeated, tag = "13")]
+    pub positive_delta: ::prost::alloc::vec::Vec<i64>,
+}
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct Bucket {
+    #[prost(uint64, tag = "1")]
+    pub cumulative_count: u64,
+    #[prost(double, tag = "2")]
+    pub upper_bound: f64,
+}
+/// A run of consecutive buckets, starting `offset` buckets after the end of the
+/// previous span (or at bucket index `offset` for the first).
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct BucketSpan {
+    #[prost(sint32, tag = "1")]
+    pub offset: i32,
+    #[prost(uint32, tag = "2")]
+    pub length: u32,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Metric {
+    #[prost(message, repeated, tag = "1")]
+    pub label: ::prost::alloc::vec::Vec<LabelPair>,
+    #[prost(message, optional, tag = "2")]
+    pub gauge: ::core::option::Option<Gauge>,
+    #[prost(message, optional, tag = "3")]
+    pub counter: ::core::option::Option<Counter>,
+    #[prost(message, optional, tag = "7")]
+    pub histogram: ::core::option::Option<Histogram>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct MetricFamily {
+    #[prost(string, tag = "1")]
+    pub name: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub help: ::prost::alloc::string::String,
+    #[prost(enumeration = "MetricType", tag = "3")]
+    pub r#type: i32,
+    #[prost(message, repeated, tag = "4")]
+    pub metric: ::prost::alloc::vec::Vec<Metric>,
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum MetricType {
+    Counter = 0,
+    Gauge = 1,
+    Summary = 2,
+    Untyped = 3,
+    Histogram = 4,
+    GaugeHistogram = 5,
+}
+impl MetricType {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Self::Counter => "COUNTER",
+            Self::Gauge => "GAUGE",
+            Self::Summary => "SUMMARY",
+            Self::Untyped => "UNTYPED",
+            Self::Histogram => "HISTOGRAM",
+            Self::GaugeHistogram => "GAUGE_HISTOGRAM",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "COUNTER" => Some(Self::Counter),
+            "GAUGE" => Some(Self::Gauge),
+            "SUMMARY" => Some(Self::Summary),
+            "UNTYPED" => Some(Self::Untyped),
+            "HISTOGRAM" => Some(Self::Histogram),
+            "GAUGE_HISTOGRAM" => Some(Self::GaugeHistogram),
+            _ => None,
+        }
+    }
+}
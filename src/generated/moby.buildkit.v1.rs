@@ -152,7 +152,13 @@ pub struct BuildHistoryRecord {
     pub r#ref: ::prost::alloc::string::String,
     #[prost(string, tag = "2")]
     pub frontend: ::prost::alloc::string::String,
-    /// fields 3–4 (FrontendAttrs, Exporters) skipped
+    #[prost(map = "string, string", tag = "3")]
+    pub frontend_attrs: ::std::collections::HashMap<
+        ::prost::alloc::string::String,
+        ::prost::alloc::string::String,
+    >,
+    #[prost(message, repeated, tag = "4")]
+    pub exporters: ::prost::alloc::vec::Vec<Exporter>,
     #[prost(message, optional, tag = "5")]
     pub error: ::core::option::Option<BuildError>,
     #[prost(message, optional, tag = "6")]
@@ -175,6 +181,44 @@ pub struct BuildError {
     #[prost(string, tag = "2")]
     pub message: ::prost::alloc::string::String,
 }
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Exporter {
+    #[prost(string, tag = "1")]
+    pub r#type: ::prost::alloc::string::String,
+    #[prost(map = "string, string", tag = "2")]
+    pub attrs: ::std::collections::HashMap<
+        ::prost::alloc::string::String,
+        ::prost::alloc::string::String,
+    >,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct StatusRequest {
+    #[prost(string, tag = "1")]
+    pub r#ref: ::prost::alloc::string::String,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct StatusResponse {
+    /// fields 2–4 (statuses, logs, warnings) skipped
+    #[prost(message, repeated, tag = "1")]
+    pub vertexes: ::prost::alloc::vec::Vec<Vertex>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Vertex {
+    #[prost(string, tag = "1")]
+    pub digest: ::prost::alloc::string::String,
+    /// field 2 (inputs) skipped
+    #[prost(string, tag = "3")]
+    pub name: ::prost::alloc::string::String,
+    #[prost(bool, tag = "4")]
+    pub cached: bool,
+    #[prost(message, optional, tag = "5")]
+    pub started: ::core::option::Option<::prost_types::Timestamp>,
+    #[prost(message, optional, tag = "6")]
+    pub completed: ::core::option::Option<::prost_types::Timestamp>,
+    /// field 8 (progressGroup) skipped
+    #[prost(string, tag = "7")]
+    pub error: ::prost::alloc::string::String,
+}
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
 #[repr(i32)]
 pub enum BuildHistoryEventType {
@@ -393,5 +437,29 @@ pub mod control_client {
                 );
             self.inner.server_streaming(req, path, codec).await
         }
+        pub async fn status(
+            &mut self,
+            request: impl tonic::IntoRequest<super::StatusRequest>,
+        ) -> std::result::Result<
+            tonic::Response<tonic::codec::Streaming<super::StatusResponse>>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::unknown(
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/moby.buildkit.v1.Control/Status",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("moby.buildkit.v1.Control", "Status"));
+            self.inner.server_streaming(req, path, codec).await
+        }
     }
 }
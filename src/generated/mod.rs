@@ -1,2 +1,28 @@
 // Generated by `make generate`. Do not edit.
 include!("moby.buildkit.v1.rs");
+
+// Nested like the `opentelemetry.proto.collector.*` packages so the trace
+// service's references to shared metrics types resolve. prost keeps the proto
+// oneof names (`AnyValue.value.string_value`, ...).
+#[allow(clippy::enum_variant_names)]
+pub mod otlp {
+    pub mod metrics {
+        pub mod v1 {
+            include!("opentelemetry.proto.collector.metrics.v1.rs");
+        }
+    }
+    pub mod trace {
+        pub mod v1 {
+            include!("opentelemetry.proto.collector.trace.v1.rs");
+        }
+    }
+}
+
+pub mod prometheus {
+    include!("prometheus.rs");
+}
+
+// Prometheus client_model, for the protobuf exposition format.
+pub mod client_model {
+    include!("io.prometheus.client.rs");
+}
@@ -0,0 +1,170 @@
+//! Liveness and readiness state for `/healthz` and `/readyz`.
+//!
+//! Each daemon records the outcome of every Control API call and the time of its
+//! last fully successful scrape. A daemon is ready when that scrape is recent
+//! and its last `Info` call answered; the agent is ready when any (or, by
+//! policy, every) daemon is.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Outcome of the most recent call of one RPC.
+#[derive(Clone, Debug, Serialize)]
+pub struct RpcOutcome {
+    pub ok: bool,
+    /// gRPC status code name when the call failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    pub at_seconds: f64,
+}
+
+#[derive(Default)]
+struct State {
+    rpcs: BTreeMap<&'static str, RpcOutcome>,
+    last_success: Option<SystemTime>,
+}
+
+/// How per-daemon readiness combines into the agent's.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReadyPolicy {
+    /// Ready while at least one daemon is, so one dead daemon does not pull
+    /// an agent still serving the others.
+    Any,
+    /// Ready only while every daemon is.
+    All,
+}
+
+impl ReadyPolicy {
+    pub fn ready<'a>(self, builders: impl IntoIterator<Item = &'a Readiness>) -> bool {
+        let mut builders = builders.into_iter();
+        match self {
+            Self::Any => builders.any(|r| r.ready),
+            Self::All => builders.all(|r| r.ready),
+        }
+    }
+}
+
+/// Health state of one daemon. Cheap to clone.
+#[derive(Clone, Default)]
+pub struct Health(Arc<Mutex<State>>);
+
+/// Readiness of one daemon as served by `GET /readyz`.
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub last_scrape_success_seconds: Option<f64>,
+    pub rpcs: BTreeMap<&'static str, RpcOutcome>,
+}
+
+impl Health {
+    pub fn record_rpc(&self, rpc: &'static str, result: Result<(), &tonic::Status>) {
+        let outcome = RpcOutcome {
+            ok: result.is_ok(),
+            code: result.err().map(|s| format!("{:?}", s.code())),
+            message: result.err().map(|s| s.message().to_string()),
+            at_seconds: unix_seconds(SystemTime::now()),
+        };
+        self.0.lock().unwrap().rpcs.insert(rpc, outcome);
+    }
+
+    pub fn record_scrape_success(&self, now: SystemTime) {
+        self.0.lock().unwrap().last_success = Some(now);
+    }
+
+    pub fn last_scrape_success(&self) -> Option<SystemTime> {
+        self.0.lock().unwrap().last_success
+    }
+
+    /// Ready if the last successful scrape is at most `max_age` old and the
+    /// last `info` call succeeded.
+    pub fn readiness(&self, now: SystemTime, max_age: Duration) -> Readiness {
+        let state = self.0.lock().unwrap();
+        let recent = state
+            .last_success
+            .and_then(|t| now.duration_since(t).ok())
+            .is_some_and(|age| age <= max_age);
+        let info_ok = state.rpcs.get("info").is_some_and(|o| o.ok);
+        Readiness {
+            ready: recent && info_ok,
+            last_scrape_success_seconds: state.last_success.map(unix_seconds),
+            rpcs: state.rpcs.clone(),
+        }
+    }
+}
+
+fn unix_seconds(t: SystemTime) -> f64 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_AGE: Duration = Duration::from_secs(45);
+
+    #[test]
+    fn not_ready_before_first_scrape() {
+        let health = Health::default();
+        assert!(!health.readiness(SystemTime::now(), MAX_AGE).ready);
+    }
+
+    #[test]
+    fn ready_after_successful_scrape() {
+        let health = Health::default();
+        let now = SystemTime::now();
+        health.record_rpc("info", Ok(()));
+        health.record_scrape_success(now);
+        let r = health.readiness(now + Duration::from_secs(10), MAX_AGE);
+        assert!(r.ready);
+        assert!(r.rpcs["info"].ok);
+    }
+
+    #[test]
+    fn stale_scrape_is_not_ready() {
+        let health = Health::default();
+        let now = SystemTime::now();
+        health.record_rpc("info", Ok(()));
+        health.record_scrape_success(now);
+        assert!(
+            !health
+                .readiness(now + Duration::from_secs(60), MAX_AGE)
+                .ready
+        );
+    }
+
+    #[test]
+    fn ready_policy_combines_daemons() {
+        let readiness = |ready| Readiness {
+            ready,
+            last_scrape_success_seconds: None,
+            rpcs: BTreeMap::new(),
+        };
+        let mixed = [readiness(true), readiness(false)];
+        assert!(ReadyPolicy::Any.ready(&mixed));
+        assert!(!ReadyPolicy::All.ready(&mixed));
+        assert!(!ReadyPolicy::Any.ready(&[readiness(false)]));
+        assert!(ReadyPolicy::All.ready(&[readiness(true)]));
+    }
+
+    #[test]
+    fn failed_info_is_not_ready() {
+        let health = Health::default();
+        let now = SystemTime::now();
+        health.record_scrape_success(now);
+        let status = tonic::Status::unavailable("connection refused");
+        health.record_rpc("info", Err(&status));
+        let r = health.readiness(now, MAX_AGE);
+        assert!(!r.ready);
+        assert_eq!(r.rpcs["info"].code.as_deref(), Some("Unavailable"));
+        assert_eq!(
+            r.rpcs["info"].message.as_deref(),
+            Some("connection refused")
+        );
+    }
+}
@@ -0,0 +1,520 @@
+//! Live build history: holds a long-lived `ListenBuildHistory` subscription and
+//! records builds as BuildKit reports them, resubscribing when the stream drops.
+//!
+//! Each build seen starting live also gets a `Status` stream, which reports
+//! vertex timing; the first vertex start splits the build's duration into time
+//! queued and time executing, and the vertexes become child spans when builds
+//! are traced.
+
+use crate::client::Connection;
+use crate::dedup::SeenRefs;
+use crate::events::EventLog;
+use crate::generated::{
+    BuildHistoryEventType, BuildHistoryRecord, BuildHistoryRequest, StatusRequest, Vertex,
+};
+use crate::labels::BuildLabels;
+use crate::metrics::{record_build_phases, record_builds, record_running, running_age};
+use crate::traces::Tracer;
+
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::{mpsc, watch};
+use tokio::task::AbortHandle;
+
+const RESUBSCRIBE_DELAY_MIN: Duration = Duration::from_secs(1);
+const RESUBSCRIBE_DELAY_MAX: Duration = Duration::from_secs(30);
+/// How often counted refs are written to the dedup state file, if changed.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Builds BuildKit has reported as started but not yet completed, keyed by ref.
+/// Shared between the history subscription (writer), the scrape loop (refreshes
+/// age gauges) and the `/builds` endpoint.
+#[derive(Clone)]
+pub struct RunningBuilds {
+    builder: Arc<str>,
+    builds: Arc<Mutex<HashMap<String, BuildHistoryRecord>>>,
+    /// Vertexes from each build's `Status` stream; the STARTED record carries
+    /// no step counts, so progress comes from these.
+    vertexes: BuildVertexes,
+}
+
+/// One in-flight build as served by `GET /builds`.
+#[derive(Debug, Serialize)]
+pub struct RunningBuildView {
+    #[serde(rename = "ref")]
+    pub r#ref: String,
+    pub frontend: String,
+    pub created_at_seconds: Option<i64>,
+    pub age_seconds: Option<f64>,
+    pub completed_steps: i32,
+    pub total_steps: i32,
+    /// `completed_steps / total_steps`, absent until the build's `Status`
+    /// stream reports a vertex.
+    pub progress: Option<f64>,
+}
+
+impl RunningBuilds {
+    pub fn new(builder: &str) -> Self {
+        Self {
+            builder: builder.into(),
+            builds: Arc::default(),
+            vertexes: BuildVertexes::default(),
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<BuildHistoryRecord> {
+        self.builds.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Refresh the in-flight gauges, e.g. so ages keep growing between events.
+    pub fn record(&self, now: SystemTime) {
+        record_running(&self.builder, &self.snapshot(), now);
+    }
+
+    /// Per-build progress, oldest build first.
+    pub fn view(&self, now: SystemTime) -> Vec<RunningBuildView> {
+        let mut builds: Vec<_> = self
+            .snapshot()
+            .into_iter()
+            .map(|r| {
+                let (completed_steps, total_steps) = self.vertexes.steps(&r.r#ref);
+                RunningBuildView {
+                    age_seconds: running_age(&r, now).map(|d| d.as_secs_f64()),
+                    created_at_seconds: r.created_at.map(|t| t.seconds),
+                    progress: (total_steps > 0)
+                        .then(|| f64::from(completed_steps) / f64::from(total_steps)),
+                    completed_steps,
+                    total_steps,
+                    frontend: r.frontend,
+                    r#ref: r.r#ref,
+                }
+            })
+            .collect();
+        builds.sort_by_key(|b| b.created_at_seconds);
+        builds
+    }
+
+    fn update(&self, f: impl FnOnce(&mut HashMap<String, BuildHistoryRecord>)) {
+        let records = {
+            let mut running = self.builds.lock().unwrap();
+            f(&mut running);
+            running.values().cloned().collect::<Vec<_>>()
+        };
+        record_running(&self.builder, &records, SystemTime::now());
+    }
+}
+
+/// Latest state of every vertex of each build being tracked through `Status`,
+/// keyed by ref and then vertex digest, with the task following each stream.
+#[derive(Clone, Default)]
+struct BuildVertexes(Arc<Mutex<HashMap<String, TrackedBuild>>>);
+
+#[derive(Default)]
+struct TrackedBuild {
+    vertexes: BTreeMap<String, Vertex>,
+    task: Option<AbortHandle>,
+}
+
+impl Drop for TrackedBuild {
+    fn drop(&mut self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
+}
+
+impl BuildVertexes {
+    /// Start tracking `build_ref`; false if it already is.
+    fn track(&self, build_ref: &str) -> bool {
+        let mut builds = self.0.lock().unwrap();
+        if builds.contains_key(build_ref) {
+            return false;
+        }
+        builds.insert(build_ref.to_string(), TrackedBuild::default());
+        true
+    }
+
+    /// Start tracking `build_ref` and follow its `Status` stream, unless it
+    /// already is. The task is aborted once the build is taken or cleared.
+    fn follow(&self, conn: &Connection, build_ref: &str) {
+        if !self.track(build_ref) {
+            return;
+        }
+        let task = tokio::spawn(track_vertices(
+            conn.clone(),
+            build_ref.to_string(),
+            self.clone(),
+        ));
+        match self.0.lock().unwrap().get_mut(build_ref) {
+            Some(build) => build.task = Some(task.abort_handle()),
+            None => task.abort(),
+        }
+    }
+
+    /// Fold in vertex updates, ignoring builds no longer tracked. Each update
+    /// carries a vertex's full state, so it replaces the previous one.
+    fn observe(&self, build_ref: &str, vertexes: Vec<Vertex>) {
+        if let Some(known) = self.0.lock().unwrap().get_mut(build_ref) {
+            for vertex in vertexes {
+                known.vertexes.insert(vertex.digest.clone(), vertex);
+            }
+        }
+    }
+
+    /// Completed and total vertexes of `build_ref` so far, counted as BuildKit
+    /// counts steps in the COMPLETE record.
+    fn steps(&self, build_ref: &str) -> (i32, i32) {
+        let builds = self.0.lock().unwrap();
+        let Some(build) = builds.get(build_ref) else {
+            return (0, 0);
+        };
+        let completed = build.vertexes.values().filter(|v| v.completed.is_some());
+        (completed.count() as i32, build.vertexes.len() as i32)
+    }
+
+    /// Stop tracking `build_ref`, returning its vertexes ordered by digest.
+    fn take(&self, build_ref: &str) -> Vec<Vertex> {
+        let build = self.0.lock().unwrap().remove(build_ref);
+        build
+            .map(|mut b| std::mem::take(&mut b.vertexes))
+            .into_iter()
+            .flat_map(BTreeMap::into_values)
+            .collect()
+    }
+
+    /// Stop tracking every build, such as those whose `COMPLETE` was lost
+    /// with the subscription.
+    fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+}
+
+/// Hands builds to a per-build output's background writer. Sending never
+/// waits: once `SinkQueue::CAPACITY` builds are pending, further ones are
+/// dropped with a warning, so a slow output never holds up build counting.
+pub struct SinkQueue<T>(mpsc::Sender<T>);
+
+impl<T> Clone for SinkQueue<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> SinkQueue<T> {
+    const CAPACITY: usize = 1024;
+
+    /// The queue and the receiver its writer drains.
+    pub fn new() -> (Self, mpsc::Receiver<T>) {
+        let (tx, rx) = mpsc::channel(Self::CAPACITY);
+        (Self(tx), rx)
+    }
+
+    /// Queue `item` for build `build_ref`; `output` names the output in the warning.
+    pub fn send(&self, output: &str, builder: &str, build_ref: &str, item: T) {
+        if self.0.try_send(item).is_err() {
+            tracing::warn!(builder, r#ref = %build_ref, output, "output queue full, dropping build");
+        }
+    }
+}
+
+/// Per-build outputs besides the counters, fed every newly counted build.
+#[derive(Clone, Default)]
+pub struct BuildSinks {
+    pub tracer: Option<Tracer>,
+    pub events: Option<EventLog>,
+}
+
+impl BuildSinks {
+    fn record(
+        &self,
+        builder: &str,
+        build_labels: &BuildLabels,
+        record: &BuildHistoryRecord,
+        vertexes: &[Vertex],
+    ) {
+        if let Some(tracer) = &self.tracer {
+            tracer.record(builder, record, vertexes);
+        }
+        if let Some(events) = &self.events {
+            events.record(builder, build_labels, record);
+        }
+    }
+}
+
+/// Earliest start among `vertexes`.
+fn first_started(vertexes: &[Vertex]) -> Option<SystemTime> {
+    vertexes
+        .iter()
+        .filter_map(|v| SystemTime::try_from(v.started?).ok())
+        .min()
+}
+
+/// Follow the `Status` stream of one build until it ends, recording its
+/// vertexes. Failures only cost the build its phase timings and vertex spans,
+/// and are common (fast builds can finish before the stream opens), so they
+/// are logged at debug level and kept out of the scrape metrics and health.
+async fn track_vertices(conn: Connection, build_ref: String, vertexes: BuildVertexes) {
+    let Ok(mut client) = conn.client() else {
+        return;
+    };
+    let request = tonic::Request::new(StatusRequest {
+        r#ref: build_ref.clone(),
+    });
+    let mut stream = match client.status(request).await {
+        Ok(stream) => stream.into_inner(),
+        Err(status) => {
+            tracing::debug!(r#ref = %build_ref, err = %status, "build status unavailable");
+            return;
+        }
+    };
+    loop {
+        match stream.message().await {
+            Ok(Some(resp)) => vertexes.observe(&build_ref, resp.vertexes),
+            Ok(None) => return,
+            Err(status) => {
+                tracing::debug!(r#ref = %build_ref, err = %status, "build status stream failed");
+                return;
+            }
+        }
+    }
+}
+
+/// Tail build history until `shutdown` turns true. Each (re)subscription
+/// replays the retained history before switching to live events, so refs
+/// already counted are tracked in `seen_refs` to keep counters moving forward
+/// only. Counted refs are flushed before returning.
+pub async fn watch(
+    conn: Connection,
+    running: RunningBuilds,
+    mut seen_refs: SeenRefs,
+    build_labels: Arc<BuildLabels>,
+    sinks: BuildSinks,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut delay = RESUBSCRIBE_DELAY_MIN;
+    loop {
+        let result = subscribe(
+            &conn,
+            &mut seen_refs,
+            &build_labels,
+            &sinks,
+            &running,
+            &mut shutdown,
+            &mut delay,
+        )
+        .await;
+        seen_refs.flush().await;
+        if *shutdown.borrow() {
+            return;
+        }
+        match result {
+            Ok(()) => tracing::info!(
+                builder = %running.builder,
+                "build history stream closed, resubscribing"
+            ),
+            Err(e) => {
+                tracing::warn!(
+                    builder = %running.builder,
+                    err = %e,
+                    retry_in = ?delay,
+                    "build history stream failed"
+                );
+                conn.check(&e);
+            }
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            // Refs were flushed above.
+            _ = shutdown.changed() => return,
+        }
+        delay = (delay * 2).min(RESUBSCRIBE_DELAY_MAX);
+    }
+}
+
+/// Consume one subscription until it ends or `shutdown` changes. `delay` is
+/// reset once the stream is open so a long-lived stream that later drops
+/// retries promptly.
+async fn subscribe(
+    conn: &Connection,
+    seen_refs: &mut SeenRefs,
+    build_labels: &BuildLabels,
+    sinks: &BuildSinks,
+    running: &RunningBuilds,
+    shutdown: &mut watch::Receiver<bool>,
+    delay: &mut Duration,
+) -> Result<()> {
+    let vertexes = &running.vertexes;
+    let open = async {
+        let mut client = conn.wait_client().await;
+        let request = tonic::Request::new(BuildHistoryRequest {
+            early_exit: false,
+            ..Default::default()
+        });
+        conn.observe("build_history", client.listen_build_history(request))
+            .await
+    };
+    let mut stream = tokio::select! {
+        stream = open => stream?,
+        _ = shutdown.changed() => return Ok(()),
+    };
+    tracing::debug!("subscribed to build history");
+    *delay = RESUBSCRIBE_DELAY_MIN;
+    // The subscription replays active builds as STARTED, so rebuild from scratch.
+    running.update(HashMap::clear);
+    vertexes.clear();
+
+    let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        let message = tokio::select! {
+            message = stream.message() => message,
+            _ = flush.tick() => {
+                seen_refs.flush().await;
+                continue;
+            }
+            _ = shutdown.changed() => break,
+        };
+        let event = match message {
+            Ok(Some(event)) => event,
+            Ok(None) => break,
+            Err(status) => {
+                conn.stream_failed("build_history", &status);
+                return Err(status.into());
+            }
+        };
+        let kind = event.r#type();
+        let Some(record) = event.record else { continue };
+        match kind {
+            BuildHistoryEventType::Started => {
+                vertexes.follow(conn, &record.r#ref);
+                running.update(|m| {
+                    m.insert(record.r#ref.clone(), record);
+                });
+            }
+            BuildHistoryEventType::Complete => {
+                running.update(|m| {
+                    m.remove(&record.r#ref);
+                });
+                let build_vertexes = vertexes.take(&record.r#ref);
+                if seen_refs.insert(&record) {
+                    if let Some(first_vertex) = first_started(&build_vertexes) {
+                        record_build_phases(&running.builder, build_labels, &record, first_vertex);
+                    }
+                    sinks.record(&running.builder, build_labels, &record, &build_vertexes);
+                    record_builds(&running.builder, build_labels, &[record]);
+                }
+            }
+            BuildHistoryEventType::Deleted => seen_refs.forget(&record.r#ref),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(seconds: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    fn vertex(started: Option<i64>) -> Vertex {
+        Vertex {
+            started: started.map(|seconds| prost_types::Timestamp { seconds, nanos: 0 }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn view_lists_oldest_build_first_with_progress() {
+        let running = RunningBuilds::new("pool-0");
+        // STARTED records carry no step counts.
+        let build = |r#ref: &str, created: i64| BuildHistoryRecord {
+            r#ref: r#ref.into(),
+            frontend: "dockerfile.v0".into(),
+            created_at: Some(prost_types::Timestamp {
+                seconds: created,
+                nanos: 0,
+            }),
+            ..Default::default()
+        };
+        running.update(|m| {
+            m.insert("new".into(), build("new", 190));
+            m.insert("old".into(), build("old", 100));
+        });
+        running.vertexes.track("old");
+        running.vertexes.track("new");
+        let step = |digest: &str, completed: Option<i64>| Vertex {
+            digest: digest.into(),
+            completed: completed.map(|seconds| prost_types::Timestamp { seconds, nanos: 0 }),
+            ..vertex(Some(100))
+        };
+        running
+            .vertexes
+            .observe("old", vec![step("a", Some(110)), step("b", None)]);
+        // Later updates replace a vertex's state rather than adding a step.
+        running.vertexes.observe(
+            "old",
+            vec![step("b", Some(120)), step("c", Some(130)), step("d", None)],
+        );
+
+        let view = running.view(at(200));
+        let refs: Vec<_> = view.iter().map(|b| b.r#ref.as_str()).collect();
+        assert_eq!(refs, ["old", "new"]);
+        assert_eq!(view[0].created_at_seconds, Some(100));
+        assert_eq!(view[0].age_seconds, Some(100.0));
+        assert_eq!((view[0].completed_steps, view[0].total_steps), (3, 4));
+        assert_eq!(view[0].progress, Some(0.75));
+        assert_eq!(view[1].age_seconds, Some(10.0));
+        // No vertex reported yet.
+        assert_eq!(view[1].progress, None);
+    }
+
+    #[test]
+    fn first_started_ignores_pending_vertexes() {
+        let vertexes = [vertex(None), vertex(Some(120)), vertex(Some(100))];
+        assert_eq!(first_started(&vertexes), Some(at(100)));
+        assert_eq!(first_started(&[vertex(None)]), None);
+    }
+
+    #[test]
+    fn build_vertexes_keep_latest_state_until_taken() {
+        let vertexes = BuildVertexes::default();
+        assert!(vertexes.track("b1"));
+        assert!(!vertexes.track("b1"));
+        let mut running = vertex(Some(150));
+        running.digest = "sha256:a".into();
+        vertexes.observe("b1", vec![running.clone()]);
+        let done = Vertex {
+            completed: Some(prost_types::Timestamp {
+                seconds: 160,
+                nanos: 0,
+            }),
+            ..running
+        };
+        vertexes.observe("b1", vec![done.clone()]);
+        vertexes.observe("untracked", vec![vertex(Some(100))]);
+        assert_eq!(vertexes.take("b1"), vec![done]);
+        assert!(vertexes.take("b1").is_empty());
+        assert!(vertexes.take("untracked").is_empty());
+    }
+
+    #[tokio::test]
+    async fn status_tasks_are_aborted_when_cleared() {
+        let vertexes = BuildVertexes::default();
+        let mut tasks = Vec::new();
+        for build_ref in ["b1", "b2"] {
+            assert!(vertexes.track(build_ref));
+            let task = tokio::spawn(std::future::pending::<()>());
+            vertexes.0.lock().unwrap().get_mut(build_ref).unwrap().task = Some(task.abort_handle());
+            tasks.push(task);
+        }
+        vertexes.take("b1");
+        assert!(tasks.remove(0).await.unwrap_err().is_cancelled());
+        vertexes.clear();
+        assert!(tasks.remove(0).await.unwrap_err().is_cancelled());
+        assert!(vertexes.0.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,454 @@
+//! Labels derived from a build history record for the build counters and histograms.
+//!
+//! Anything taken from a record is user-controlled (a custom frontend can be any
+//! image ref), so values are normalised and checked against an allowlist before
+//! they become label values. Frontend attributes (`target`, `label:*`,
+//! `build-arg:*`) are only copied into labels the operator maps explicitly.
+//! Failure reasons come from a fixed set of rule names and gRPC codes, never
+//! from the error message itself.
+
+use crate::generated::{BuildError, BuildHistoryRecord, Exporter};
+
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use std::sync::OnceLock;
+
+/// Value of the `frontend` label for frontends outside the allowlist.
+const FRONTEND_OTHER: &str = "other";
+/// Value of the `frontend` label for records without a frontend.
+const FRONTEND_NONE: &str = "none";
+
+/// Exporter types reported as-is in the `exporter` label; others become `other`.
+const KNOWN_EXPORTERS: &[&str] = &["image", "local", "tar", "oci", "docker"];
+
+/// Label names the agent sets itself.
+const RESERVED_LABELS: &[&str] = &["builder", "frontend", "exporter", "reason", "le"];
+
+/// Failure reasons matched on `BuildError.message` after any configured rules.
+const DEFAULT_FAILURE_RULES: &[(&str, &str)] = &[
+    ("step_failed", r"process .* did not complete successfully"),
+    (
+        "registry",
+        r"failed to resolve source metadata|failed to push|pull access denied",
+    ),
+    (
+        "dockerfile",
+        r"dockerfile parse error|failed to read dockerfile",
+    ),
+];
+
+/// A frontend attribute copied into a label, parsed from `label=attribute`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AttrLabel {
+    label: String,
+    attr: String,
+}
+
+impl AttrLabel {
+    /// Parse `label=attribute`, e.g. `team=label:com.example.team` or
+    /// `project=build-arg:CI_PROJECT`.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let Some((label, attr)) = spec.split_once('=') else {
+            bail!("attribute label {spec:?} must be label=attribute");
+        };
+        if !is_label_name(label) {
+            bail!("invalid Prometheus label name {label:?}");
+        }
+        if RESERVED_LABELS.contains(&label) {
+            bail!("label {label:?} is reserved");
+        }
+        if attr.is_empty() {
+            bail!("attribute label {label:?} needs a frontend attribute");
+        }
+        Ok(Self {
+            label: label.to_string(),
+            attr: attr.to_string(),
+        })
+    }
+
+    /// Parse every spec, rejecting label names mapped more than once: they
+    /// would put duplicate labels on one series.
+    pub fn parse_all(specs: &[String]) -> Result<Vec<Self>> {
+        let mut attrs: Vec<Self> = Vec::with_capacity(specs.len());
+        for spec in specs {
+            let attr = Self::parse(spec)?;
+            if attrs.iter().any(|a| a.label == attr.label) {
+                bail!("label {:?} is mapped more than once", attr.label);
+            }
+            attrs.push(attr);
+        }
+        Ok(attrs)
+    }
+}
+
+/// A valid Prometheus label name that is not reserved for internal use (`__` prefix).
+pub fn is_label_name(label: &str) -> bool {
+    label.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !label.starts_with("__")
+}
+
+/// Maps a build error message to a `reason`, parsed from `reason=regex`.
+#[derive(Clone, Debug)]
+pub struct FailureRule {
+    reason: String,
+    pattern: Regex,
+}
+
+impl FailureRule {
+    /// Parse `reason=regex`, e.g. `step_failed=process .* did not complete successfully`.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let Some((reason, pattern)) = spec.split_once('=') else {
+            bail!("failure rule {spec:?} must be reason=regex");
+        };
+        if reason.is_empty() {
+            bail!("failure rule {spec:?} needs a reason");
+        }
+        Ok(Self {
+            reason: reason.to_string(),
+            pattern: Regex::new(pattern).with_context(|| format!("failure rule {reason:?}"))?,
+        })
+    }
+
+    /// Parse a rules file: one `reason=regex` per line, skipping blank lines
+    /// and `#` comments.
+    pub fn parse_lines(text: &str) -> Result<Vec<Self>> {
+        text.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+            .map(|(n, line)| Self::parse(line.trim()).with_context(|| format!("line {}", n + 1)))
+            .collect()
+    }
+}
+
+fn default_failure_rules() -> &'static [FailureRule] {
+    static RULES: OnceLock<Vec<FailureRule>> = OnceLock::new();
+    RULES.get_or_init(|| {
+        DEFAULT_FAILURE_RULES
+            .iter()
+            .map(|(reason, pattern)| FailureRule {
+                reason: reason.to_string(),
+                pattern: Regex::new(pattern).expect("valid default failure rule"),
+            })
+            .collect()
+    })
+}
+
+/// Which extra labels build metrics carry. The default adds only `builder`.
+#[derive(Debug, Default)]
+pub struct BuildLabels {
+    /// Allowed `frontend` label values; `None` leaves the label off.
+    frontends: Option<Vec<String>>,
+    /// Add an `exporter` label.
+    exporter: bool,
+    attrs: Vec<AttrLabel>,
+    /// Checked before the default rules.
+    failure_rules: Vec<FailureRule>,
+}
+
+impl BuildLabels {
+    /// Label builds by frontend, keeping only normalised names in `allowlist`.
+    pub fn with_frontends(mut self, allowlist: &[String]) -> Self {
+        self.frontends = Some(allowlist.iter().map(|f| normalize_frontend(f)).collect());
+        self
+    }
+
+    /// Label builds by how their result was exported.
+    pub fn with_exporter(mut self) -> Self {
+        self.exporter = true;
+        self
+    }
+
+    /// Copy frontend attributes into labels. Builds without the attribute get
+    /// an empty value.
+    pub fn with_attrs(mut self, attrs: Vec<AttrLabel>) -> Self {
+        self.attrs = attrs;
+        self
+    }
+
+    /// Classify failures with `rules` before the built-in ones.
+    pub fn with_failure_rules(mut self, rules: Vec<FailureRule>) -> Self {
+        self.failure_rules = rules;
+        self
+    }
+
+    /// `reason` label of a failed build: the first rule matching the error
+    /// message, else the snake_case gRPC code name (`deadline_exceeded`).
+    pub fn failure_reason(&self, error: &BuildError) -> String {
+        let rule = self
+            .failure_rules
+            .iter()
+            .chain(default_failure_rules())
+            .find(|r| r.pattern.is_match(&error.message));
+        match rule {
+            Some(r) => r.reason.clone(),
+            None => snake_case(&format!("{:?}", tonic::Code::from_i32(error.code))),
+        }
+    }
+
+    /// Reasons of the configured and built-in rules, in matching order.
+    pub fn rule_reasons(&self) -> Vec<&str> {
+        let mut reasons: Vec<&str> = Vec::new();
+        for rule in self.failure_rules.iter().chain(default_failure_rules()) {
+            if !reasons.contains(&rule.reason.as_str()) {
+                reasons.push(&rule.reason);
+            }
+        }
+        reasons
+    }
+
+    /// Labels for one build: `builder` first, then the configured extras.
+    pub fn for_build(&self, builder: &str, record: &BuildHistoryRecord) -> Vec<(String, String)> {
+        let mut labels = vec![("builder".to_string(), builder.to_string())];
+        if let Some(allowlist) = &self.frontends {
+            let frontend = normalize_frontend(&record.frontend);
+            let value = if frontend.is_empty() {
+                FRONTEND_NONE.to_string()
+            } else if allowlist.contains(&frontend) {
+                frontend
+            } else {
+                FRONTEND_OTHER.to_string()
+            };
+            labels.push(("frontend".to_string(), value));
+        }
+        if self.exporter {
+            labels.push(("exporter".to_string(), exporter_kind(&record.exporters)));
+        }
+        for a in &self.attrs {
+            let value = record
+                .frontend_attrs
+                .get(&a.attr)
+                .cloned()
+                .unwrap_or_default();
+            labels.push((a.label.clone(), value));
+        }
+        labels
+    }
+}
+
+/// Sorted, comma-separated exporter kinds of a build. An `image` export with
+/// `push=true` is `registry`; a build with no exporters is `cacheonly`.
+fn exporter_kind(exporters: &[Exporter]) -> String {
+    let mut kinds: Vec<&str> = exporters
+        .iter()
+        .map(|e| match e.r#type.as_str() {
+            "image" if e.attrs.get("push").is_some_and(|v| v == "true") => "registry",
+            t if KNOWN_EXPORTERS.contains(&t) => t,
+            _ => "other",
+        })
+        .collect();
+    if kinds.is_empty() {
+        return "cacheonly".to_string();
+    }
+    kinds.sort_unstable();
+    kinds.dedup();
+    kinds.join(",")
+}
+
+fn snake_case(name: &str) -> String {
+    let mut out = String::with_capacity(name.len() + 4);
+    for (i, c) in name.chars().enumerate() {
+        if c.is_ascii_uppercase() && i > 0 {
+            out.push('_');
+        }
+        out.push(c.to_ascii_lowercase());
+    }
+    out
+}
+
+/// Lowercase and drop any tag or digest, so `docker/dockerfile:1.7@sha256:...`
+/// becomes `docker/dockerfile`.
+fn normalize_frontend(frontend: &str) -> String {
+    let name = frontend.trim().split('@').next().unwrap_or_default();
+    let name = match name.rsplit_once(':') {
+        // A colon before the last slash is a registry port, not a tag.
+        Some((repo, tag)) if !tag.contains('/') => repo,
+        _ => name,
+    };
+    name.to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(frontend: &str) -> BuildHistoryRecord {
+        BuildHistoryRecord {
+            frontend: frontend.into(),
+            ..Default::default()
+        }
+    }
+
+    fn label(labels: &BuildLabels, record: &BuildHistoryRecord, key: &str) -> Option<String> {
+        labels
+            .for_build("default", record)
+            .into_iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v)
+    }
+
+    fn frontend_label(labels: &BuildLabels, frontend: &str) -> Option<String> {
+        label(labels, &build(frontend), "frontend")
+    }
+
+    fn exporter(r#type: &str, attrs: &[(&str, &str)]) -> Exporter {
+        Exporter {
+            r#type: r#type.into(),
+            attrs: attrs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn frontend_label_is_off_by_default() {
+        assert_eq!(
+            frontend_label(&BuildLabels::default(), "dockerfile.v0"),
+            None
+        );
+    }
+
+    #[test]
+    fn frontends_outside_allowlist_become_other() {
+        let labels = BuildLabels::default()
+            .with_frontends(&["dockerfile.v0".into(), "docker/dockerfile".into()]);
+        assert_eq!(
+            frontend_label(&labels, "dockerfile.v0").as_deref(),
+            Some("dockerfile.v0")
+        );
+        assert_eq!(
+            frontend_label(&labels, "Docker/Dockerfile:1.7@sha256:abc").as_deref(),
+            Some("docker/dockerfile")
+        );
+        assert_eq!(
+            frontend_label(&labels, "gateway.v0").as_deref(),
+            Some("other")
+        );
+        assert_eq!(frontend_label(&labels, "").as_deref(), Some("none"));
+    }
+
+    #[test]
+    fn registry_port_is_not_a_tag() {
+        assert_eq!(
+            normalize_frontend("registry:5000/frontend"),
+            "registry:5000/frontend"
+        );
+        assert_eq!(
+            normalize_frontend("registry:5000/frontend:v1"),
+            "registry:5000/frontend"
+        );
+    }
+
+    #[test]
+    fn maps_frontend_attributes_to_labels() {
+        let labels = BuildLabels::default().with_attrs(vec![
+            AttrLabel::parse("team=label:com.example.team").unwrap(),
+            AttrLabel::parse("target=target").unwrap(),
+        ]);
+        let mut record = build("dockerfile.v0");
+        record
+            .frontend_attrs
+            .insert("label:com.example.team".into(), "payments".into());
+        assert_eq!(label(&labels, &record, "team").as_deref(), Some("payments"));
+        assert_eq!(label(&labels, &record, "target").as_deref(), Some(""));
+    }
+
+    #[test]
+    fn rejects_invalid_attribute_labels() {
+        assert!(AttrLabel::parse("target").is_err());
+        assert!(AttrLabel::parse("my-team=label:team").is_err());
+        assert!(AttrLabel::parse("builder=target").is_err());
+        assert!(AttrLabel::parse("__name=target").is_err());
+        assert!(AttrLabel::parse("team=").is_err());
+        let specs = ["team=label:a".to_string(), "team=label:b".to_string()];
+        assert!(AttrLabel::parse_all(&specs).is_err());
+        assert_eq!(AttrLabel::parse_all(&specs[..1]).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn classifies_exporters() {
+        assert_eq!(exporter_kind(&[]), "cacheonly");
+        assert_eq!(exporter_kind(&[exporter("image", &[])]), "image");
+        assert_eq!(
+            exporter_kind(&[exporter("image", &[("push", "true")])]),
+            "registry"
+        );
+        assert_eq!(
+            exporter_kind(&[
+                exporter("oci", &[]),
+                exporter("local", &[]),
+                exporter("oci", &[])
+            ]),
+            "local,oci"
+        );
+        assert_eq!(exporter_kind(&[exporter("custom", &[])]), "other");
+    }
+
+    fn error(code: i32, message: &str) -> BuildError {
+        BuildError {
+            code,
+            message: message.into(),
+        }
+    }
+
+    #[test]
+    fn classifies_failures_by_message_then_code() {
+        let labels = BuildLabels::default();
+        assert_eq!(
+            labels.failure_reason(&error(
+                2,
+                r#"failed to solve: process "/bin/sh -c make" did not complete successfully: exit code: 2"#
+            )),
+            "step_failed"
+        );
+        assert_eq!(
+            labels.failure_reason(&error(
+                2,
+                "failed to solve: alpine:3.99: failed to resolve source metadata"
+            )),
+            "registry"
+        );
+        assert_eq!(
+            labels.failure_reason(&error(4, "timeout")),
+            "deadline_exceeded"
+        );
+        assert_eq!(labels.failure_reason(&error(2, "boom")), "unknown");
+    }
+
+    #[test]
+    fn configured_failure_rules_come_first() {
+        let labels = BuildLabels::default()
+            .with_failure_rules(vec![FailureRule::parse("oom=exit code: 137").unwrap()]);
+        let err = error(
+            2,
+            r#"process "/bin/sh -c make" did not complete successfully: exit code: 137"#,
+        );
+        assert_eq!(labels.failure_reason(&err), "oom");
+        assert_eq!(
+            labels.rule_reasons(),
+            ["oom", "step_failed", "registry", "dockerfile"]
+        );
+    }
+
+    #[test]
+    fn parses_failure_rules_file() {
+        let text = "# user errors\n\nlint=error; see (hadolint|shellcheck)\n  oom=exit code: 137\n";
+        let rules = FailureRule::parse_lines(text).unwrap();
+        let labels = BuildLabels::default().with_failure_rules(rules);
+        assert_eq!(
+            labels.failure_reason(&error(2, "error; see hadolint output")),
+            "lint"
+        );
+        assert_eq!(labels.failure_reason(&error(2, "exit code: 137")), "oom");
+        let err = FailureRule::parse_lines("ok=x\nbad=(").unwrap_err();
+        assert!(format!("{err:#}").contains("line 2"));
+    }
+
+    #[test]
+    fn rejects_invalid_failure_rules() {
+        assert!(FailureRule::parse("no separator").is_err());
+        assert!(FailureRule::parse("=x").is_err());
+        assert!(FailureRule::parse("bad=(").is_err());
+    }
+}
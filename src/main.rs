@@ -1,39 +1,74 @@
 //! BuildKit metrics agent: application that connects to BuildKit over gRPC (Control API,
-//! unix socket), periodically scrapes info, workers, cache, and build history, and
-//! serves Prometheus metrics at `GET /metrics`.
+//! unix socket or TCP with optional mutual TLS), periodically scrapes info, workers and
+//! cache, tails build history, and serves Prometheus metrics at `GET /metrics` (optionally
+//! also pushing them over OTLP).
 
+mod client;
+mod dedup;
+mod events;
+mod exposition;
 mod generated;
+mod health;
+mod history;
+mod labels;
 mod metrics;
+mod otlp;
+mod push;
+#[cfg(test)]
+mod stand_in;
+mod statsd;
+mod store;
+mod target;
+mod traces;
 
-use generated::{
-    control_client::ControlClient, BuildHistoryEventType, BuildHistoryRequest, DiskUsageRequest,
-    DiskUsageResponse, InfoRequest, InfoResponse, ListWorkersRequest, ListWorkersResponse,
-};
-
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use axum::http::StatusCode;
 use clap::Parser;
-use hyper_util::rt::TokioIo;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use tokio::net::UnixStream;
-use tonic::transport::{Endpoint, Uri};
-use tower::service_fn;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use metrics::scrape_and_record;
+use client::TlsFiles;
+use dedup::SeenRefs;
+use health::ReadyPolicy;
+use history::BuildSinks;
+use labels::{AttrLabel, BuildLabels, FailureRule};
+use metrics_util::layers::FanoutBuilder;
+use store::Store;
+use target::Target;
+use traces::Tracer;
 
 /// BuildKit Metrics Agent — a lightweight application that scrapes and exposes BuildKit metrics.
 #[derive(Parser, Debug)]
 #[command(name = "buildkit-metrics-agent")]
 struct Args {
-    /// BuildKit gRPC endpoint (unix socket path or unix:///path)
+    /// BuildKit gRPC endpoint (unix socket path, unix:///path or tcp://host:port), optionally
+    /// prefixed with `name=` for the `builder` label. Repeat (or comma-separate) to monitor
+    /// several daemons.
     #[arg(
         long,
         env = "BUILDKIT_ADDR",
+        value_delimiter = ',',
         default_value = "unix:///run/buildkit/buildkitd.sock"
     )]
-    addr: String,
+    addr: Vec<String>,
+
+    /// CA certificate (PEM) to verify a tcp:// daemon (default: system roots)
+    #[arg(long, env = "BUILDKIT_TLS_CA_CERT")]
+    tls_ca_cert: Option<PathBuf>,
+
+    /// Client certificate (PEM) for mutual TLS
+    #[arg(long, env = "BUILDKIT_TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+
+    /// Client key (PEM) for mutual TLS
+    #[arg(long, env = "BUILDKIT_TLS_KEY")]
+    tls_key: Option<PathBuf>,
+
+    /// Server name to verify in the daemon's certificate (default: address host)
+    #[arg(long, env = "BUILDKIT_TLS_SERVER_NAME")]
+    tls_server_name: Option<String>,
 
     /// Metrics HTTP listen address
     #[arg(long, env = "METRICS_ADDR", default_value = "0.0.0.0:9090")]
@@ -42,6 +77,150 @@ struct Args {
     /// Scrape interval for BuildKit Control API
     #[arg(long, env = "SCRAPE_INTERVAL_SECS", default_value = "15")]
     scrape_interval_secs: u64,
+
+    /// `/readyz` fails once the last successful scrape is older than this many intervals
+    #[arg(long, env = "READY_SCRAPE_INTERVALS", default_value = "3")]
+    ready_scrape_intervals: u32,
+
+    /// With several daemons, `/readyz` succeeds while `any` of them is ready or
+    /// only while `all` are
+    #[arg(long, env = "READY_POLICY", value_enum, default_value = "any")]
+    ready_policy: ReadyPolicy,
+
+    /// Add a `frontend` label to build counters and histograms
+    #[arg(long, env = "FRONTEND_LABEL")]
+    frontend_label: bool,
+
+    /// Frontends kept as `frontend` label values (tags and digests stripped); others become
+    /// `other`
+    #[arg(
+        long,
+        env = "FRONTEND_ALLOWLIST",
+        value_delimiter = ',',
+        default_value = "dockerfile.v0,gateway.v0,docker/dockerfile"
+    )]
+    frontend_allowlist: Vec<String>,
+
+    /// Add an `exporter` label to build metrics (image, registry, local, tar, oci, docker,
+    /// cacheonly)
+    #[arg(long, env = "EXPORTER_LABEL")]
+    exporter_label: bool,
+
+    /// Copy a frontend attribute into a build metric label, as `label=attribute` (e.g.
+    /// `team=label:com.example.team`, `project=build-arg:CI_PROJECT`). Repeat or comma-separate.
+    #[arg(long, env = "ATTR_LABELS", value_delimiter = ',')]
+    attr_label: Vec<String>,
+
+    /// Classify failed builds whose error message matches a regex, as `reason=regex`. Checked
+    /// in order before the built-in rules; unmatched failures use the gRPC code name.
+    /// Repeat the flag for several rules (the regex may contain any character, `,` and `;`
+    /// included), or use `--failure-rules-file`.
+    #[arg(long, env = "FAILURE_RULES")]
+    failure_rule: Vec<String>,
+
+    /// File of failure rules, one `reason=regex` per line (blank lines and `#` comments
+    /// ignored), checked after `--failure-rule`
+    #[arg(long, env = "FAILURE_RULES_FILE")]
+    failure_rules_file: Option<PathBuf>,
+
+    /// Bucket upper bounds for a histogram, as `metric=b1,b2,...` (e.g.
+    /// `buildkit_build_duration_seconds=10,60,600,3600,7200`). Separate several with `;`.
+    #[arg(long, env = "HISTOGRAM_BUCKETS", value_delimiter = ';')]
+    histogram_buckets: Vec<String>,
+
+    /// Also keep native (sparse, exponential) histograms and serve them, with every other
+    /// metric, in the protobuf exposition format to scrapers that ask for it (Prometheus with
+    /// native histograms enabled). Text scrapes keep the classic buckets.
+    #[arg(long, env = "NATIVE_HISTOGRAMS")]
+    native_histograms: bool,
+
+    /// Drop gauge series (e.g. a cache record type removed by a prune) once they have not been
+    /// reported for this many scrape intervals
+    #[arg(long, env = "STALE_SCRAPE_INTERVALS", default_value = "3")]
+    stale_scrape_intervals: u32,
+
+    /// Also push metrics to an OpenTelemetry Collector at this endpoint (e.g.
+    /// `http://otel-collector:4317` for gRPC, `http://otel-collector:4318` for HTTP)
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+
+    /// OTLP transport: grpc or http/protobuf
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_PROTOCOL", value_enum, default_value = "grpc")]
+    otlp_protocol: otlp::Protocol,
+
+    /// How often to push metrics over OTLP
+    #[arg(long, env = "OTLP_INTERVAL_SECS", default_value = "60")]
+    otlp_interval_secs: u64,
+
+    /// Also send each completed build as a trace (with a span per step when the build was
+    /// seen starting) to the OTLP endpoint
+    #[arg(long, env = "OTLP_TRACES")]
+    otlp_traces: bool,
+
+    /// `service.name` resource attribute of pushed metrics and traces
+    #[arg(long, env = "OTEL_SERVICE_NAME", default_value = "buildkit-metrics-agent")]
+    otlp_service_name: String,
+
+    /// Extra resource attributes of pushed metrics and traces, as `key=value`. Repeat or comma-separate.
+    #[arg(long, env = "OTEL_RESOURCE_ATTRIBUTES", value_delimiter = ',')]
+    otlp_resource_attribute: Vec<String>,
+
+    /// Push metrics to a Prometheus Pushgateway at this URL (e.g. `http://pushgateway:9091`),
+    /// for hosts that may be gone before they are scraped
+    #[arg(long, env = "PUSHGATEWAY_URL")]
+    pushgateway_url: Option<String>,
+
+    /// Push metrics with Prometheus remote write to this URL (e.g.
+    /// `http://prometheus:9090/api/v1/write`)
+    #[arg(long, env = "REMOTE_WRITE_URL")]
+    remote_write_url: Option<String>,
+
+    /// `job` of pushed metrics: the Pushgateway job, or a label added by remote write
+    #[arg(long, env = "PUSH_JOB", default_value = "buildkit-metrics-agent")]
+    push_job: String,
+
+    /// Extra grouping labels of pushed metrics, as `name=value` (e.g. `instance=ci-runner-3`).
+    /// Repeat or comma-separate.
+    #[arg(long, env = "PUSH_GROUPING", value_delimiter = ',')]
+    push_grouping: Vec<String>,
+
+    /// How often to push to the Pushgateway or remote write; a final push runs on shutdown
+    #[arg(long, env = "PUSH_INTERVAL_SECS", default_value = "15")]
+    push_interval_secs: u64,
+
+    /// Also send metrics to a StatsD / DogStatsD server over UDP (e.g. `127.0.0.1:8125`)
+    #[arg(long, env = "STATSD_ADDR")]
+    statsd_addr: Option<String>,
+
+    /// StatsD dialect: dogstatsd (labels become tags) or statsd (labels dropped)
+    #[arg(long, env = "STATSD_FLAVOR", value_enum, default_value = "dogstatsd")]
+    statsd_flavor: statsd::Flavor,
+
+    /// Tags added to every DogStatsD metric, as `key:value`. Repeat or comma-separate.
+    #[arg(long, env = "STATSD_TAGS", value_delimiter = ',')]
+    statsd_tags: Vec<String>,
+
+    /// Write one JSON line per completed build to `stdout`, `file:<path>` (rotated by size) or
+    /// `unixgram:<path>` (one datagram per build)
+    #[arg(long, env = "BUILD_EVENT_LOG")]
+    build_event_log: Option<String>,
+
+    /// Rotate the build event log file once it would grow past this size
+    #[arg(long, env = "BUILD_EVENT_LOG_MAX_BYTES", default_value = "104857600")]
+    build_event_log_max_bytes: u64,
+
+    /// Rotated build event log files to keep (`<path>.1` is the newest)
+    #[arg(long, env = "BUILD_EVENT_LOG_KEEP", default_value = "5")]
+    build_event_log_keep: u32,
+
+    /// File to persist counted build refs in, so restarts do not re-count retained history.
+    /// With several daemons each gets its own file, named after the builder.
+    #[arg(long, env = "STATE_FILE")]
+    state_file: Option<PathBuf>,
+
+    /// How long counted build refs are remembered (match BuildKit's history max age)
+    #[arg(long, env = "DEDUP_MAX_AGE_SECS", default_value = "172800")]
+    dedup_max_age_secs: u64,
 }
 
 #[tokio::main]
@@ -54,116 +233,279 @@ async fn main() -> Result<()> {
         .init();
 
     let args = Args::parse();
+    // Installed up front so a failure stops startup instead of shutdown.
+    let shutdown = shutdown_signal()?;
 
-    let socket_path = args
+    let tls = TlsFiles {
+        ca_cert: args.tls_ca_cert.clone(),
+        cert: args.tls_cert.clone(),
+        key: args.tls_key.clone(),
+        server_name: args.tls_server_name.clone(),
+    };
+    let targets = args
         .addr
-        .strip_prefix("unix://")
-        .unwrap_or(args.addr.as_str());
-    let path = PathBuf::from(socket_path);
+        .iter()
+        .map(|spec| Target::parse(spec, &tls))
+        .collect::<Result<Vec<_>>>()?;
+    let mut names = BTreeSet::new();
+    for target in &targets {
+        if !names.insert(target.name.as_str()) {
+            bail!("duplicate builder name {}", target.name);
+        }
+    }
 
-    let metrics_handle = metrics::install_recorder();
     let scrape_interval = Duration::from_secs(args.scrape_interval_secs);
+    let buckets = args
+        .histogram_buckets
+        .iter()
+        .map(|spec| metrics::Buckets::parse(spec))
+        .collect::<Result<Vec<_>>>()?;
+    let gauge_idle_timeout = scrape_interval * args.stale_scrape_intervals.max(1);
+    let mut fanout = FanoutBuilder::default();
+    let mut sinks = BuildSinks::default();
+    let store = Store::new(
+        Some(gauge_idle_timeout),
+        &buckets,
+        args.native_histograms,
+    );
+    if args.otlp_endpoint.is_some() || args.native_histograms {
+        fanout = fanout.add_recorder(store.clone());
+    }
+    if let Some(endpoint) = &args.otlp_endpoint {
+        let attributes = args
+            .otlp_resource_attribute
+            .iter()
+            .map(|spec| otlp::parse_attribute(spec))
+            .collect::<Result<Vec<_>>>()?;
+        let transport = otlp::Transport::new(endpoint, args.otlp_protocol)?;
+        let resources = otlp::Resources::new(&args.otlp_service_name, &attributes);
+        let exporter =
+            otlp::MetricsExporter::new(transport.clone(), resources.clone(), store.clone());
+        tracing::info!(%endpoint, protocol = ?args.otlp_protocol, "pushing metrics over OTLP");
+        exporter.spawn(Duration::from_secs(args.otlp_interval_secs.max(1)));
+        if args.otlp_traces {
+            sinks.tracer = Some(Tracer::spawn(transport, resources));
+        }
+    } else if args.otlp_traces {
+        bail!("--otlp-traces requires --otlp-endpoint");
+    }
+    if let Some(addr) = &args.statsd_addr {
+        let statsd = statsd::Statsd::connect(addr, args.statsd_flavor, &args.statsd_tags)?;
+        tracing::info!(%addr, flavor = ?args.statsd_flavor, "sending metrics to statsd");
+        fanout = fanout.add_recorder(statsd);
+    }
+    let metrics_handle = metrics::install_recorder(gauge_idle_timeout, &buckets, fanout);
+    let dedup_max_age = Duration::from_secs(args.dedup_max_age_secs);
 
-    // Tracks build refs we've already counted so counters only move forward
-    // even as BuildKit's history window evicts old records.
-    let seen_refs: Arc<Mutex<HashSet<String>>> = Arc::new(Mutex::new(HashSet::new()));
-
-    // Background: periodically scrape BuildKit Control API and update metrics.
-    // Initial sleep gives buildkitd time to create its socket before the first attempt.
-    let path_clone = path.clone();
-    tokio::spawn(async move {
-        tokio::time::sleep(Duration::from_secs(1)).await;
-        loop {
-            if let Err(e) = scrape_once(&path_clone, Arc::clone(&seen_refs)).await {
-                tracing::warn!(err = %e, "scrape failed");
-            }
-            tokio::time::sleep(scrape_interval).await;
+    let grouping = args
+        .push_grouping
+        .iter()
+        .map(|spec| push::parse_grouping(spec))
+        .collect::<Result<Vec<_>>>()?;
+    let mut destinations = Vec::new();
+    if let Some(url) = &args.pushgateway_url {
+        destinations.push(push::Destination::pushgateway(url, &args.push_job, &grouping)?);
+    }
+    if let Some(url) = &args.remote_write_url {
+        destinations.push(push::Destination::remote_write(url, &args.push_job, &grouping)?);
+    }
+    let pushers: Vec<_> = destinations
+        .into_iter()
+        .map(|destination| {
+            tracing::info!(?destination, "pushing metrics");
+            let pusher = Arc::new(push::Pusher::new(destination, metrics_handle.clone())?);
+            Arc::clone(&pusher).spawn(Duration::from_secs(args.push_interval_secs.max(1)));
+            Ok(pusher)
+        })
+        .collect::<Result<_>>()?;
+
+    let mut build_labels = BuildLabels::default();
+    if args.frontend_label {
+        build_labels = build_labels.with_frontends(&args.frontend_allowlist);
+    }
+    if args.exporter_label {
+        build_labels = build_labels.with_exporter();
+    }
+    let attrs = AttrLabel::parse_all(&args.attr_label)?;
+    let mut rules = args
+        .failure_rule
+        .iter()
+        .map(|spec| FailureRule::parse(spec))
+        .collect::<Result<Vec<_>>>()?;
+    if let Some(path) = &args.failure_rules_file {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("read failure rules {}", path.display()))?;
+        rules.extend(FailureRule::parse_lines(&text)?);
+    }
+    let build_labels = build_labels.with_attrs(attrs).with_failure_rules(rules);
+    let build_labels = Arc::new(build_labels);
+
+    if let Some(spec) = &args.build_event_log {
+        let output = events::Output::parse(spec)?;
+        sinks.events = Some(events::EventLog::open(
+            &output,
+            args.build_event_log_max_bytes,
+            args.build_event_log_keep,
+        )?);
+    }
+
+    let multiple = targets.len() > 1;
+    let state_files: Vec<_> = targets
+        .iter()
+        .map(|target| match &args.state_file {
+            Some(path) if multiple => Some(per_builder_path(path, &target.name)),
+            other => other.clone(),
+        })
+        .collect();
+    let mut state_file_owners = BTreeMap::new();
+    for (target, path) in targets.iter().zip(&state_files) {
+        let Some(path) = path else { continue };
+        if let Some(other) = state_file_owners.insert(path, &target.name) {
+            bail!(
+                "builders {other:?} and {:?} would share state file {}; rename one",
+                target.name,
+                path.display()
+            );
         }
-    });
+    }
+
+    let (stop_history, history_stopped) = tokio::sync::watch::channel(false);
+    let mut targets_state = BTreeMap::new();
+    let mut history_tasks = Vec::new();
+    for (target, state_file) in targets.into_iter().zip(state_files) {
+        let seen_refs = SeenRefs::new(dedup_max_age, state_file);
+        tracing::info!(builder = %target.name, addr = %target.addr, "monitoring buildkitd");
+        let name = target.name.clone();
+        let (state, history) = target.spawn(
+            scrape_interval,
+            seen_refs,
+            Arc::clone(&build_labels),
+            sinks.clone(),
+            history_stopped.clone(),
+        );
+        targets_state.insert(name, state);
+        history_tasks.push(history);
+    }
+
+    let targets_state = Arc::new(targets_state);
+    let ready_max_age = scrape_interval * args.ready_scrape_intervals;
+    let ready_policy = args.ready_policy;
 
-    // HTTP server for Prometheus /metrics
+    // HTTP server for Prometheus /metrics, in-flight builds and health probes
     let listener = tokio::net::TcpListener::bind(&args.metrics_addr).await?;
     tracing::info!(addr = %args.metrics_addr, "metrics listening");
     let handle = metrics_handle.clone();
-    let app = axum::Router::new().route(
-        "/metrics",
-        axum::routing::get(move || {
-            let h = handle.clone();
-            async move {
-                let body = h.render();
-                (
-                    [(
-                        axum::http::header::CONTENT_TYPE,
-                        "text/plain; charset=utf-8",
-                    )],
-                    body,
-                )
-            }
-        }),
-    );
-    axum::serve(listener, app.into_make_service()).await?;
-
-    Ok(())
-}
-
-async fn scrape_once(socket_path: &Path, seen_refs: Arc<Mutex<HashSet<String>>>) -> Result<()> {
-    let path = socket_path.to_path_buf();
-    let channel = Endpoint::try_from("http://[::]:0")?
-        .connect_with_connector(service_fn(move |_: Uri| {
-            let path = path.clone();
-            async move {
-                let stream = UnixStream::connect(path).await?;
-                Ok::<_, std::io::Error>(TokioIo::new(stream))
-            }
-        }))
+    let native_store = args.native_histograms.then(|| store.clone());
+    let builds_state = Arc::clone(&targets_state);
+    let app = axum::Router::new()
+        .route(
+            "/metrics",
+            axum::routing::get(move |headers: axum::http::HeaderMap| {
+                let h = handle.clone();
+                let native_store = native_store.clone();
+                async move {
+                    let accept = headers
+                        .get(axum::http::header::ACCEPT)
+                        .and_then(|v| v.to_str().ok())
+                        .unwrap_or_default();
+                    match native_store {
+                        Some(store) if exposition::accepts_protobuf(accept) => (
+                            [(axum::http::header::CONTENT_TYPE, exposition::CONTENT_TYPE)],
+                            exposition::encode(&store.snapshot()),
+                        ),
+                        _ => (
+                            [(
+                                axum::http::header::CONTENT_TYPE,
+                                "text/plain; charset=utf-8",
+                            )],
+                            h.render().into_bytes(),
+                        ),
+                    }
+                }
+            }),
+        )
+        .route(
+            "/builds",
+            axum::routing::get(move || {
+                let now = SystemTime::now();
+                let view: BTreeMap<_, _> = builds_state
+                    .iter()
+                    .map(|(name, state)| (name.clone(), state.running.view(now)))
+                    .collect();
+                async move { axum::Json(view) }
+            }),
+        )
+        .route("/healthz", axum::routing::get(|| async { "ok" }))
+        .route(
+            "/readyz",
+            axum::routing::get(move || {
+                let now = SystemTime::now();
+                let builders: BTreeMap<_, _> = targets_state
+                    .iter()
+                    .map(|(name, state)| (name.clone(), state.health.readiness(now, ready_max_age)))
+                    .collect();
+                let ready = ready_policy.ready(builders.values());
+                let status = if ready {
+                    StatusCode::OK
+                } else {
+                    StatusCode::SERVICE_UNAVAILABLE
+                };
+                let body = serde_json::json!({ "ready": ready, "builders": builders });
+                async move { (status, axum::Json(body)) }
+            }),
+        );
+    axum::serve(listener, app.into_make_service())
+        .with_graceful_shutdown(shutdown)
         .await?;
 
-    let mut client = ControlClient::new(channel);
-
-    let info: InfoResponse = client
-        .info(tonic::Request::new(InfoRequest {}))
-        .await?
-        .into_inner();
-    let workers: ListWorkersResponse = client
-        .list_workers(tonic::Request::new(ListWorkersRequest { filter: vec![] }))
-        .await?
-        .into_inner();
-    let disk: DiskUsageResponse = client
-        .disk_usage(tonic::Request::new(DiskUsageRequest {
-            filter: vec![],
-            age_limit: 0,
-        }))
-        .await?
-        .into_inner();
-    // Stream existing build history then close (EarlyExit stops the stream
-    // once all current records have been sent).
-    let mut build_stream = client
-        .listen_build_history(tonic::Request::new(BuildHistoryRequest {
-            early_exit: true,
-            ..Default::default()
-        }))
-        .await?
-        .into_inner();
-
-    // Collect completed records from the stream before touching the lock.
-    let mut completed = Vec::new();
-    while let Some(event) = build_stream.message().await? {
-        if event.r#type() == BuildHistoryEventType::Complete {
-            if let Some(record) = event.record {
-                completed.push(record);
-            }
+    // Write counted refs to the state files so a restarted agent does not count them again.
+    stop_history.send_replace(true);
+    for history in history_tasks {
+        if let Err(e) = history.await {
+            tracing::warn!(err = %e, "build history task failed");
         }
     }
 
-    // Filter to only refs we haven't counted yet — lock is not held across any await.
-    let new_records = {
-        let mut seen = seen_refs.lock().unwrap();
-        completed
-            .into_iter()
-            .filter(|r| seen.insert(r.r#ref.clone()))
-            .collect::<Vec<_>>()
-    };
+    // Final push so builds counted since the last interval are not lost with the host.
+    for pusher in &pushers {
+        if let Err(e) = pusher.push().await {
+            tracing::warn!(err = %e, "final metrics push failed");
+        }
+    }
 
-    scrape_and_record(info, workers, disk, new_records);
     Ok(())
 }
+
+/// Install the SIGTERM handler; the returned future resolves on Ctrl-C or SIGTERM.
+fn shutdown_signal() -> Result<impl std::future::Future<Output = ()>> {
+    let mut term = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .context("installing SIGTERM handler")?;
+    Ok(async move {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = term.recv() => {}
+        }
+        tracing::info!("shutting down");
+    })
+}
+
+/// `state.json` + `pool-0` -> `state.pool-0.json`, with path separators in the
+/// builder name replaced so every builder's file sits next to the configured one.
+fn per_builder_path(path: &Path, builder: &str) -> PathBuf {
+    let safe: String = builder
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{stem}.{safe}.{}", ext.to_string_lossy()),
+        None => format!("{stem}.{safe}"),
+    };
+    path.with_file_name(name)
+}
@@ -1,9 +1,16 @@
 //! Prometheus metrics for BuildKit status (info, workers, cache, builds).
 
-use crate::generated::{BuildHistoryRecord, DiskUsageResponse, InfoResponse, ListWorkersResponse};
-use metrics_exporter_prometheus::{Matcher, PrometheusHandle};
+use crate::generated::{
+    BuildError, BuildHistoryRecord, DiskUsageResponse, GcPolicy, InfoResponse, ListWorkersResponse,
+    UsageRecord,
+};
+use crate::labels::BuildLabels;
+use anyhow::{bail, Context};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use metrics_util::layers::FanoutBuilder;
+use metrics_util::MetricKindMask;
 use std::sync::OnceLock;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 static RECORDER: OnceLock<PrometheusHandle> = OnceLock::new();
 
@@ -11,32 +18,135 @@ const BUILD_DURATION_BUCKETS: &[f64] = &[
     1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0,
 ];
 
-pub fn install_recorder() -> PrometheusHandle {
+/// Standard worker label keys set by buildkitd (`org.mobyproject.buildkit.worker.*`).
+const WORKER_LABEL_EXECUTOR: &str = "org.mobyproject.buildkit.worker.executor";
+const WORKER_LABEL_SNAPSHOTTER: &str = "org.mobyproject.buildkit.worker.snapshotter";
+const WORKER_LABEL_HOSTNAME: &str = "org.mobyproject.buildkit.worker.hostname";
+const WORKER_LABEL_NETWORK: &str = "org.mobyproject.buildkit.worker.network";
+
+/// Upper bounds (seconds since last use) for the cache idle-time distribution: 1h, 1d, 7d, 30d.
+const CACHE_IDLE_BUCKETS: &[u64] = &[3600, 86_400, 604_800, 2_592_000];
+
+/// Upper bounds for the cache record usage-count distribution.
+const CACHE_USAGE_COUNT_BUCKETS: &[i64] = &[0, 1, 2, 5, 10, 100];
+
+const CACHE_HIT_RATIO_BUCKETS: &[f64] = &[0.0, 0.1, 0.25, 0.5, 0.75, 0.9, 0.95, 1.0];
+
+const BUILD_STEPS_BUCKETS: &[f64] = &[1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0];
+
+const RPC_DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Histograms and their default buckets. Each can be overridden with [`Buckets`].
+const HISTOGRAMS: &[(&str, &[f64])] = &[
+    ("buildkit_build_duration_seconds", BUILD_DURATION_BUCKETS),
+    ("buildkit_build_queue_seconds", BUILD_DURATION_BUCKETS),
+    ("buildkit_build_execution_seconds", BUILD_DURATION_BUCKETS),
+    ("buildkit_build_cache_hit_ratio", CACHE_HIT_RATIO_BUCKETS),
+    ("buildkit_build_steps", BUILD_STEPS_BUCKETS),
+    ("buildkit_agent_scrape_duration_seconds", RPC_DURATION_BUCKETS),
+];
+
+/// Bucket upper bounds for one histogram, parsed from `metric=b1,b2,...`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Buckets {
+    metric: String,
+    bounds: Vec<f64>,
+}
+
+impl Buckets {
+    pub fn parse(spec: &str) -> anyhow::Result<Self> {
+        let Some((metric, bounds)) = spec.split_once('=') else {
+            bail!("histogram buckets {spec:?} must be metric=b1,b2,...");
+        };
+        if !HISTOGRAMS.iter().any(|(name, _)| *name == metric) {
+            let names: Vec<_> = HISTOGRAMS.iter().map(|(name, _)| *name).collect();
+            bail!("unknown histogram {metric:?}, expected one of {}", names.join(", "));
+        }
+        let bounds = bounds
+            .split(',')
+            .map(|b| {
+                b.trim()
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|b| b.is_finite())
+                    .with_context(|| format!("invalid bucket bound {b:?} for {metric}"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if bounds.windows(2).any(|w| w[0] >= w[1]) {
+            bail!("buckets for {metric} must be strictly increasing");
+        }
+        Ok(Self {
+            metric: metric.to_string(),
+            bounds,
+        })
+    }
+}
+
+/// Exporter configuration shared by the global recorder and tests.
+///
+/// Gauges not set again within `gauge_idle_timeout` are dropped from the render,
+/// so label sets that vanish from BuildKit (a cache record type after a prune, a
+/// removed worker) stop reporting their last value. Every gauge is set on every
+/// scrape tick, so only series the daemon no longer reports go idle.
+fn prometheus_builder(
+    gauge_idle_timeout: Option<Duration>,
+    buckets: &[Buckets],
+) -> Result<PrometheusBuilder, metrics_exporter_prometheus::BuildError> {
+    let mut builder =
+        PrometheusBuilder::new().idle_timeout(MetricKindMask::GAUGE, gauge_idle_timeout);
+    for &(metric, _) in HISTOGRAMS {
+        let bounds = histogram_bounds(metric, buckets).unwrap_or_default();
+        builder = builder.set_buckets_for_metric(Matcher::Full(metric.to_string()), bounds)?;
+    }
+    Ok(builder)
+}
+
+/// Bucket upper bounds for `metric`, or `None` if it is not a known histogram.
+/// The last override for a metric wins.
+pub fn histogram_bounds<'a>(metric: &str, buckets: &'a [Buckets]) -> Option<&'a [f64]> {
+    let &(_, default) = HISTOGRAMS.iter().find(|(name, _)| *name == metric)?;
+    Some(
+        buckets
+            .iter()
+            .rev()
+            .find(|b| b.metric == metric)
+            .map_or(default, |b| b.bounds.as_slice()),
+    )
+}
+
+pub fn install_recorder(
+    gauge_idle_timeout: Duration,
+    buckets: &[Buckets],
+    fanout: FanoutBuilder,
+) -> PrometheusHandle {
     RECORDER
         .get_or_init(|| {
-            metrics_exporter_prometheus::PrometheusBuilder::new()
-                .set_buckets_for_metric(
-                    Matcher::Full("buildkit_build_duration_seconds".to_string()),
-                    BUILD_DURATION_BUCKETS,
-                )
+            let recorder = prometheus_builder(Some(gauge_idle_timeout), buckets)
                 .expect("valid histogram buckets")
-                .install_recorder()
-                .expect("metrics recorder")
+                .build_recorder();
+            let handle = recorder.handle();
+            metrics::set_global_recorder(fanout.add_recorder(recorder).build())
+                .expect("metrics recorder");
+            handle
         })
         .clone()
 }
 
-/// Update gauges/counters from the latest Control API scrape.
-pub fn scrape_and_record(
+/// Update gauges from the latest Control API scrape (info, workers, disk usage).
+pub fn record_scrape(
+    builder: &str,
     info: InfoResponse,
     workers: ListWorkersResponse,
     disk: DiskUsageResponse,
-    builds: Vec<BuildHistoryRecord>,
+    now: SystemTime,
 ) {
     // BuildKit version info (we expose as labels or info metric)
     if let Some(v) = info.buildkit_version.as_ref() {
         metrics::gauge!(
             "buildkit_info",
+            "builder" => builder.to_string(),
             "version" => v.version.clone(),
             "revision" => v.revision.clone()
         )
@@ -45,13 +155,40 @@ pub fn scrape_and_record(
 
     // Worker count
     let n = workers.record.len() as f64;
-    metrics::gauge!("buildkit_workers_total").set(n);
+    metrics::gauge!("buildkit_workers_total", "builder" => builder.to_string()).set(n);
+
+    // Per-worker info and the platforms each worker can build for
+    for w in &workers.record {
+        let label = |key: &str| w.labels.get(key).cloned().unwrap_or_default();
+        metrics::gauge!(
+            "buildkit_worker_info",
+            "builder" => builder.to_string(),
+            "worker_id" => w.id.clone(),
+            "executor" => label(WORKER_LABEL_EXECUTOR),
+            "snapshotter" => label(WORKER_LABEL_SNAPSHOTTER),
+            "hostname" => label(WORKER_LABEL_HOSTNAME),
+            "network" => label(WORKER_LABEL_NETWORK)
+        )
+        .set(1.0);
+        for p in &w.platforms {
+            metrics::gauge!(
+                "buildkit_worker_platforms",
+                "builder" => builder.to_string(),
+                "worker_id" => w.id.clone(),
+                "os" => p.os.clone(),
+                "architecture" => p.architecture.clone(),
+                "variant" => p.variant.clone()
+            )
+            .set(1.0);
+        }
+    }
 
     // Cache / disk usage: total size and record count
     let total_size: i64 = disk.record.iter().map(|r| r.size).sum();
     let count = disk.record.len() as f64;
-    metrics::gauge!("buildkit_cache_records_total").set(count);
-    metrics::gauge!("buildkit_cache_size_bytes").set(total_size as f64);
+    metrics::gauge!("buildkit_cache_records_total", "builder" => builder.to_string()).set(count);
+    metrics::gauge!("buildkit_cache_size_bytes", "builder" => builder.to_string())
+        .set(total_size as f64);
 
     // Size by record type (e.g. snapshot, content)
     let mut by_type: std::collections::HashMap<String, i64> = std::collections::HashMap::new();
@@ -66,49 +203,303 @@ pub fn scrape_and_record(
     for (record_type, size) in by_type {
         metrics::gauge!(
             "buildkit_cache_size_by_type_bytes",
+            "builder" => builder.to_string(),
             "record_type" => record_type
         )
         .set(size as f64);
     }
 
-    // Increment counters only for builds not yet seen — callers pass only new records.
-    for r in &builds {
-        let (succeeded, failed) = if r.error.as_ref().is_some_and(|e| e.code != 0) {
-            (0u64, 1u64)
-        } else {
-            (1u64, 0u64)
-        };
-        metrics::counter!("buildkit_builds_total").increment(1);
-        metrics::counter!("buildkit_builds_succeeded_total").increment(succeeded);
-        metrics::counter!("buildkit_builds_failed_total").increment(failed);
-        metrics::counter!("buildkit_builds_cached_steps_total")
+    // Size and count by record state, and what `buildctl prune` could free
+    let mut by_state: std::collections::HashMap<(bool, bool, bool), (i64, u64)> =
+        std::collections::HashMap::new();
+    for r in &disk.record {
+        let entry = by_state.entry((r.in_use, r.shared, r.mutable)).or_insert((0, 0));
+        entry.0 += r.size;
+        entry.1 += 1;
+    }
+    for ((in_use, shared, mutable), (size, count)) in by_state {
+        let labels = [
+            ("builder", builder.to_string()),
+            ("in_use", in_use.to_string()),
+            ("shared", shared.to_string()),
+            ("mutable", mutable.to_string()),
+        ];
+        metrics::gauge!("buildkit_cache_size_by_state_bytes", &labels).set(size as f64);
+        metrics::gauge!("buildkit_cache_records_by_state_total", &labels).set(count as f64);
+    }
+    // Same rule as `buildctl du`: anything not in use is reclaimable.
+    let reclaimable: i64 = disk.record.iter().filter(|r| !r.in_use).map(|r| r.size).sum();
+    metrics::gauge!("buildkit_cache_reclaimable_bytes", "builder" => builder.to_string())
+        .set(reclaimable as f64);
+
+    // Cumulative distributions (Prometheus `le` buckets) of bytes by idle time and
+    // of records by usage count, to tune GC keepDuration against real reuse.
+    let idle: Vec<(Option<Duration>, i64)> = disk
+        .record
+        .iter()
+        .map(|r| (cache_idle(r, now), r.size))
+        .collect();
+    for &le in CACHE_IDLE_BUCKETS {
+        let limit = Duration::from_secs(le);
+        let size: i64 = idle
+            .iter()
+            .filter(|(d, _)| d.is_some_and(|d| d <= limit))
+            .map(|(_, size)| size)
+            .sum();
+        metrics::gauge!(
+            "buildkit_cache_idle_bytes",
+            "builder" => builder.to_string(),
+            "le" => le.to_string()
+        )
+        .set(size as f64);
+    }
+    metrics::gauge!(
+        "buildkit_cache_idle_bytes",
+        "builder" => builder.to_string(),
+        "le" => "+Inf"
+    )
+    .set(total_size as f64);
+    for &le in CACHE_USAGE_COUNT_BUCKETS {
+        let n = disk.record.iter().filter(|r| r.usage_count <= le).count();
+        metrics::gauge!(
+            "buildkit_cache_usage_count_records",
+            "builder" => builder.to_string(),
+            "le" => le.to_string()
+        )
+        .set(n as f64);
+    }
+    metrics::gauge!(
+        "buildkit_cache_usage_count_records",
+        "builder" => builder.to_string(),
+        "le" => "+Inf"
+    )
+    .set(count);
+
+    // GC policy rules per worker, and how far the cache is over its catch-all limit
+    for w in &workers.record {
+        for (index, rule) in w.gc_policy.iter().enumerate() {
+            let labels = [
+                ("builder", builder.to_string()),
+                ("worker_id", w.id.clone()),
+                ("rule", index.to_string()),
+                ("filters", rule.filters.join(",")),
+                ("all", rule.all.to_string()),
+            ];
+            metrics::gauge!("buildkit_worker_gc_policy_keep_duration_seconds", &labels)
+                .set(Duration::from_nanos(rule.keep_duration.max(0) as u64).as_secs_f64());
+            metrics::gauge!("buildkit_worker_gc_policy_reserved_space_bytes", &labels)
+                .set(rule.reserved_space as f64);
+            metrics::gauge!("buildkit_worker_gc_policy_max_used_space_bytes", &labels)
+                .set(rule.max_used_space as f64);
+            metrics::gauge!("buildkit_worker_gc_policy_min_free_space_bytes", &labels)
+                .set(rule.min_free_space as f64);
+        }
+        // Disk usage records carry no worker, so usage is only attributable to a
+        // worker when the daemon runs a single one.
+        if workers.record.len() != 1 {
+            continue;
+        }
+        if let Some(limit) = catch_all_limit(&w.gc_policy) {
+            metrics::gauge!(
+                "buildkit_cache_over_policy_max_bytes",
+                "builder" => builder.to_string(),
+                "worker_id" => w.id.clone()
+            )
+            .set((total_size - limit).max(0) as f64);
+        }
+    }
+}
+
+/// Time since a cache record was last used (or created, if never used).
+/// Records without either timestamp only land in the `+Inf` bucket.
+fn cache_idle(record: &UsageRecord, now: SystemTime) -> Option<Duration> {
+    let at = SystemTime::try_from(record.last_used_at.or(record.created_at)?).ok()?;
+    Some(now.duration_since(at).unwrap_or_default())
+}
+
+/// Size limit of the last unfiltered GC rule, the one BuildKit's GC applies to
+/// the whole cache once the filtered rules have run.
+fn catch_all_limit(rules: &[GcPolicy]) -> Option<i64> {
+    rules
+        .iter()
+        .rev()
+        .find(|r| r.filters.is_empty())
+        .and_then(gc_space_limit)
+}
+
+/// Cache size a GC rule prunes down to: `maxUsedSpace`, or `reservedSpace` for
+/// daemons older than BuildKit 0.17 where it was the only size limit.
+fn gc_space_limit(rule: &GcPolicy) -> Option<i64> {
+    [rule.max_used_space, rule.reserved_space]
+        .into_iter()
+        .find(|&v| v > 0)
+}
+
+/// Update build counters and the duration histogram from completed build records.
+/// Callers pass only records not yet counted.
+pub fn record_builds(builder: &str, build_labels: &BuildLabels, builds: &[BuildHistoryRecord]) {
+    for r in builds {
+        let labels = build_labels.for_build(builder, r);
+        metrics::counter!("buildkit_builds_total", &labels).increment(1);
+        let error = r.error.as_ref().filter(|e| e.code != 0);
+        let canceled = error.is_some_and(is_canceled);
+        metrics::counter!("buildkit_builds_succeeded_total", &labels)
+            .increment(u64::from(error.is_none()));
+        metrics::counter!("buildkit_builds_canceled_total", &labels).increment(u64::from(canceled));
+        // Failures are counted per reason; every rule's reason starts at zero so
+        // failure rates are 0 rather than absent before the first failure.
+        let reason = error
+            .filter(|_| !canceled)
+            .map(|e| build_labels.failure_reason(e));
+        let mut reasons = build_labels.rule_reasons();
+        if let Some(reason) = &reason {
+            if !reasons.contains(&reason.as_str()) {
+                reasons.push(reason);
+            }
+        }
+        for r in reasons {
+            let mut failed = labels.clone();
+            failed.push(("reason".to_string(), r.to_string()));
+            metrics::counter!("buildkit_builds_failed_total", &failed)
+                .increment(u64::from(reason.as_deref() == Some(r)));
+        }
+        metrics::counter!("buildkit_builds_cached_steps_total", &labels)
             .increment(r.num_cached_steps as u64);
-        metrics::counter!("buildkit_builds_total_steps_total").increment(r.num_total_steps as u64);
+        metrics::counter!("buildkit_builds_total_steps_total", &labels)
+            .increment(r.num_total_steps as u64);
+
+        // Per-build distributions, so a cache regression in some builds is not
+        // averaged away by the aggregate step counters.
+        if r.num_total_steps > 0 {
+            let ratio = f64::from(r.num_cached_steps) / f64::from(r.num_total_steps);
+            metrics::histogram!("buildkit_build_cache_hit_ratio", &labels).record(ratio);
+            metrics::histogram!("buildkit_build_steps", &labels)
+                .record(f64::from(r.num_total_steps));
+        }
+        let incomplete = r.num_completed_steps < r.num_total_steps;
+        metrics::counter!("buildkit_builds_incomplete_total", &labels)
+            .increment(u64::from(incomplete));
 
         if let (Some(created), Some(completed)) = (&r.created_at, &r.completed_at) {
             let Ok(created) = SystemTime::try_from(*created) else { continue };
             let Ok(completed) = SystemTime::try_from(*completed) else { continue };
             if let Ok(duration) = completed.duration_since(created) {
-                metrics::histogram!("buildkit_build_duration_seconds")
+                metrics::histogram!("buildkit_build_duration_seconds", &labels)
                     .record(duration.as_secs_f64());
             }
         }
     }
 }
 
+/// Split a completed build's duration at its first vertex start: time from
+/// submission until the solver started work, and time spent executing.
+pub fn record_build_phases(
+    builder: &str,
+    build_labels: &BuildLabels,
+    record: &BuildHistoryRecord,
+    first_vertex: SystemTime,
+) {
+    let labels = build_labels.for_build(builder, record);
+    let at = |t: Option<prost_types::Timestamp>| SystemTime::try_from(t?).ok();
+    if let Some(created) = at(record.created_at) {
+        let queued = first_vertex.duration_since(created).unwrap_or_default();
+        metrics::histogram!("buildkit_build_queue_seconds", &labels)
+            .record(queued.as_secs_f64());
+    }
+    if let Some(completed) = at(record.completed_at) {
+        let executing = completed.duration_since(first_vertex).unwrap_or_default();
+        metrics::histogram!("buildkit_build_execution_seconds", &labels)
+            .record(executing.as_secs_f64());
+    }
+}
+
+/// Canceled builds (google.rpc CANCELLED, or a canceled context surfacing as
+/// another code) are not failures: CI cancels superseded builds all the time.
+pub fn is_canceled(error: &BuildError) -> bool {
+    error.code == tonic::Code::Cancelled as i32 || error.message.contains("context canceled")
+}
+
+/// Update in-flight gauges from the builds BuildKit reports as started but not
+/// yet completed. Called on every history event and every scrape tick so the
+/// oldest-build age keeps growing while a build hangs.
+pub fn record_running(builder: &str, running: &[BuildHistoryRecord], now: SystemTime) {
+    let labels = [("builder", builder.to_string())];
+    metrics::gauge!("buildkit_builds_in_progress", &labels).set(running.len() as f64);
+    let oldest = running
+        .iter()
+        .filter_map(|r| running_age(r, now))
+        .max()
+        .unwrap_or_default();
+    metrics::gauge!("buildkit_build_oldest_running_age_seconds", &labels).set(oldest.as_secs_f64());
+}
+
+/// `1` while the last scrape of buildkitd succeeded; `0` after a failed scrape
+/// or a lost connection.
+pub fn record_up(builder: &str, up: bool) {
+    let value = if up { 1.0 } else { 0.0 };
+    metrics::gauge!("buildkit_up", "builder" => builder.to_string()).set(value);
+}
+
+/// Count a reconnect after the connection to buildkitd was lost.
+pub fn record_reconnect(builder: &str) {
+    metrics::counter!("buildkit_agent_reconnects_total", "builder" => builder.to_string())
+        .increment(1);
+}
+
+/// Timestamp of the last scrape where every Control API call succeeded.
+pub fn record_scrape_success(builder: &str, now: SystemTime) {
+    let seconds = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    metrics::gauge!(
+        "buildkit_agent_last_scrape_success_timestamp_seconds",
+        "builder" => builder.to_string()
+    )
+    .set(seconds);
+}
+
+/// Duration of one Control API call and, if it failed, its gRPC status code.
+pub fn record_rpc(builder: &str, rpc: &'static str, elapsed: Duration, code: Option<tonic::Code>) {
+    metrics::histogram!(
+        "buildkit_agent_scrape_duration_seconds",
+        "builder" => builder.to_string(),
+        "rpc" => rpc
+    )
+    .record(elapsed.as_secs_f64());
+    if let Some(code) = code {
+        record_rpc_error(builder, rpc, code);
+    }
+}
+
+/// Count a failed Control API call (or a stream that broke after opening).
+pub fn record_rpc_error(builder: &str, rpc: &'static str, code: tonic::Code) {
+    metrics::counter!(
+        "buildkit_agent_scrape_errors_total",
+        "builder" => builder.to_string(),
+        "rpc" => rpc,
+        "code" => format!("{code:?}")
+    )
+    .increment(1);
+}
+
+/// Time since a build was created, if BuildKit reported a creation timestamp.
+pub fn running_age(record: &BuildHistoryRecord, now: SystemTime) -> Option<Duration> {
+    let created = SystemTime::try_from(record.created_at?).ok()?;
+    now.duration_since(created).ok()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::generated::{BuildError, BuildkitVersion, UsageRecord, WorkerRecord};
+    use crate::generated::{BuildkitVersion, Platform, WorkerRecord};
+
+    const BUILDER: &str = "default";
+    /// Unix time of every scrape in `render_with`.
+    const SCRAPE_AT: u64 = 1_700_000_000;
 
     fn recorder() -> (metrics_exporter_prometheus::PrometheusRecorder, PrometheusHandle) {
-        let rec = metrics_exporter_prometheus::PrometheusBuilder::new()
-            .set_buckets_for_metric(
-                Matcher::Full("buildkit_build_duration_seconds".to_string()),
-                BUILD_DURATION_BUCKETS,
-            )
-            .unwrap()
-            .build_recorder();
+        let rec = prometheus_builder(None, &[]).unwrap().build_recorder();
         let handle = rec.handle();
         (rec, handle)
     }
@@ -121,7 +512,11 @@ mod tests {
         disk: DiskUsageResponse,
         builds: Vec<BuildHistoryRecord>,
     ) -> String {
-        metrics::with_local_recorder(rec, || scrape_and_record(info, workers, disk, builds));
+        metrics::with_local_recorder(rec, || {
+            let now = SystemTime::UNIX_EPOCH + Duration::from_secs(SCRAPE_AT);
+            record_scrape(BUILDER, info, workers, disk, now);
+            record_builds(BUILDER, &BuildLabels::default(), &builds);
+        });
         handle.render()
     }
 
@@ -159,6 +554,7 @@ mod tests {
             num_cached_steps: cached_steps,
             num_total_steps: total_steps,
             num_completed_steps: total_steps,
+            ..Default::default()
         }
     }
 
@@ -175,7 +571,8 @@ mod tests {
             }),
         };
         let out = render_with(&rec, &handle, info, empty_workers(), empty_disk(), vec![]);
-        assert!(out.contains(r#"buildkit_info{version="0.14.1",revision="abc123"} 1"#));
+        assert!(out
+            .contains(r#"buildkit_info{builder="default",version="0.14.1",revision="abc123"} 1"#));
     }
 
     #[test]
@@ -189,7 +586,171 @@ mod tests {
             ],
         };
         let out = render_with(&rec, &handle, empty_info(), workers, empty_disk(), vec![]);
-        assert!(out.contains("buildkit_workers_total 3"));
+        assert!(out.contains(r#"buildkit_workers_total{builder="default"} 3"#));
+    }
+
+    #[test]
+    fn records_worker_info_and_platforms() {
+        let (rec, handle) = recorder();
+        let platform = |os: &str, architecture: &str, variant: &str| Platform {
+            os: os.into(),
+            architecture: architecture.into(),
+            variant: variant.into(),
+            ..Default::default()
+        };
+        let workers = ListWorkersResponse {
+            record: vec![WorkerRecord {
+                id: "w1".into(),
+                labels: [
+                    (WORKER_LABEL_EXECUTOR, "oci"),
+                    (WORKER_LABEL_SNAPSHOTTER, "overlayfs"),
+                    (WORKER_LABEL_HOSTNAME, "buildkitd-0"),
+                    (WORKER_LABEL_NETWORK, "host"),
+                ]
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+                platforms: vec![
+                    platform("linux", "amd64", ""),
+                    platform("linux", "arm64", ""),
+                    platform("linux", "arm", "v7"),
+                ],
+                ..Default::default()
+            }],
+        };
+        let out = render_with(&rec, &handle, empty_info(), workers, empty_disk(), vec![]);
+        assert!(out.contains(
+            r#"buildkit_worker_info{builder="default",worker_id="w1",executor="oci",snapshotter="overlayfs",hostname="buildkitd-0",network="host"} 1"#
+        ));
+        assert!(out.contains(
+            r#"buildkit_worker_platforms{builder="default",worker_id="w1",os="linux",architecture="arm64",variant=""} 1"#
+        ));
+        assert!(out.contains(
+            r#"buildkit_worker_platforms{builder="default",worker_id="w1",os="linux",architecture="arm",variant="v7"} 1"#
+        ));
+    }
+
+    #[test]
+    fn missing_worker_labels_are_empty() {
+        let (rec, handle) = recorder();
+        let workers = ListWorkersResponse {
+            record: vec![WorkerRecord {
+                id: "w1".into(),
+                ..Default::default()
+            }],
+        };
+        let out = render_with(&rec, &handle, empty_info(), workers, empty_disk(), vec![]);
+        assert!(out.contains(
+            r#"buildkit_worker_info{builder="default",worker_id="w1",executor="",snapshotter="",hostname="",network=""} 1"#
+        ));
+        assert!(!out.contains("buildkit_worker_platforms"));
+    }
+
+    #[test]
+    fn records_gc_policy() {
+        let (rec, handle) = recorder();
+        let workers = ListWorkersResponse {
+            record: vec![WorkerRecord {
+                id: "w1".into(),
+                gc_policy: vec![
+                    GcPolicy {
+                        keep_duration: 172_800_000_000_000,
+                        filters: vec!["type==source.local".into(), "type==exec.cachemount".into()],
+                        max_used_space: 512_000_000,
+                        ..Default::default()
+                    },
+                    GcPolicy {
+                        all: true,
+                        reserved_space: 1000,
+                        max_used_space: 1500,
+                        min_free_space: 4000,
+                        ..Default::default()
+                    },
+                ],
+                ..Default::default()
+            }],
+        };
+        let disk = DiskUsageResponse {
+            record: vec![UsageRecord {
+                size: 2000,
+                ..Default::default()
+            }],
+        };
+        let out = render_with(&rec, &handle, empty_info(), workers, disk, vec![]);
+        assert!(out.contains(
+            r#"buildkit_worker_gc_policy_keep_duration_seconds{builder="default",worker_id="w1",rule="0",filters="type==source.local,type==exec.cachemount",all="false"} 172800"#
+        ));
+        assert!(out.contains(
+            r#"buildkit_worker_gc_policy_max_used_space_bytes{builder="default",worker_id="w1",rule="1",filters="",all="true"} 1500"#
+        ));
+        assert!(out.contains(
+            r#"buildkit_worker_gc_policy_min_free_space_bytes{builder="default",worker_id="w1",rule="1",filters="",all="true"} 4000"#
+        ));
+        // Rule 1 is the catch-all: 2000 bytes against its 1500 limit. Rule 0's
+        // larger limit only bounds the records its filters match.
+        assert!(out.contains(
+            r#"buildkit_cache_over_policy_max_bytes{builder="default",worker_id="w1"} 500"#
+        ));
+    }
+
+    #[test]
+    fn cache_under_policy_max_is_zero_and_multiple_workers_are_skipped() {
+        let (rec, handle) = recorder();
+        let worker = |id: &str| WorkerRecord {
+            id: id.into(),
+            gc_policy: vec![GcPolicy {
+                all: true,
+                max_used_space: 5000,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let disk = || DiskUsageResponse {
+            record: vec![UsageRecord {
+                size: 2000,
+                ..Default::default()
+            }],
+        };
+        let workers = ListWorkersResponse {
+            record: vec![worker("w1")],
+        };
+        let out = render_with(&rec, &handle, empty_info(), workers, disk(), vec![]);
+        assert!(out.contains(
+            r#"buildkit_cache_over_policy_max_bytes{builder="default",worker_id="w1"} 0"#
+        ));
+
+        let (rec, handle) = recorder();
+        let workers = ListWorkersResponse {
+            record: vec![worker("w1"), worker("w2")],
+        };
+        let out = render_with(&rec, &handle, empty_info(), workers, disk(), vec![]);
+        assert!(!out.contains("buildkit_cache_over_policy_max_bytes"));
+    }
+
+    #[test]
+    fn legacy_reserved_space_is_policy_max() {
+        let (rec, handle) = recorder();
+        let workers = ListWorkersResponse {
+            record: vec![WorkerRecord {
+                id: "w1".into(),
+                gc_policy: vec![GcPolicy {
+                    all: true,
+                    reserved_space: 1000,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        };
+        let disk = DiskUsageResponse {
+            record: vec![UsageRecord {
+                size: 1200,
+                ..Default::default()
+            }],
+        };
+        let out = render_with(&rec, &handle, empty_info(), workers, disk, vec![]);
+        assert!(out.contains(
+            r#"buildkit_cache_over_policy_max_bytes{builder="default",worker_id="w1"} 200"#
+        ));
     }
 
     #[test]
@@ -215,12 +776,181 @@ mod tests {
             ],
         };
         let out = render_with(&rec, &handle, empty_info(), empty_workers(), disk, vec![]);
-        assert!(out.contains("buildkit_cache_records_total 3"));
-        assert!(out.contains("buildkit_cache_size_bytes 1700"));
-        assert!(out.contains(r#"buildkit_cache_size_by_type_bytes{record_type="regular"} 1500"#));
+        assert!(out.contains(r#"buildkit_cache_records_total{builder="default"} 3"#));
+        assert!(out.contains(r#"buildkit_cache_size_bytes{builder="default"} 1700"#));
+        assert!(out.contains(
+            r#"buildkit_cache_size_by_type_bytes{builder="default",record_type="regular"} 1500"#
+        ));
+        assert!(out.contains(
+            r#"buildkit_cache_size_by_type_bytes{builder="default",record_type="source.git.checkout"} 200"#
+        ));
+    }
+
+    #[test]
+    fn records_cache_state_breakdown() {
+        let (rec, handle) = recorder();
+        let disk = DiskUsageResponse {
+            record: vec![
+                UsageRecord {
+                    size: 1000,
+                    in_use: true,
+                    mutable: true,
+                    ..Default::default()
+                },
+                UsageRecord {
+                    size: 300,
+                    shared: true,
+                    ..Default::default()
+                },
+                UsageRecord {
+                    size: 200,
+                    shared: true,
+                    ..Default::default()
+                },
+                UsageRecord {
+                    size: 50,
+                    ..Default::default()
+                },
+            ],
+        };
+        let out = render_with(&rec, &handle, empty_info(), empty_workers(), disk, vec![]);
         assert!(out.contains(
-            r#"buildkit_cache_size_by_type_bytes{record_type="source.git.checkout"} 200"#
+            r#"buildkit_cache_size_by_state_bytes{builder="default",in_use="true",shared="false",mutable="true"} 1000"#
         ));
+        assert!(out.contains(
+            r#"buildkit_cache_size_by_state_bytes{builder="default",in_use="false",shared="true",mutable="false"} 500"#
+        ));
+        assert!(out.contains(
+            r#"buildkit_cache_records_by_state_total{builder="default",in_use="false",shared="true",mutable="false"} 2"#
+        ));
+        assert!(out.contains(
+            r#"buildkit_cache_records_by_state_total{builder="default",in_use="false",shared="false",mutable="false"} 1"#
+        ));
+        assert!(out.contains(r#"buildkit_cache_reclaimable_bytes{builder="default"} 550"#));
+    }
+
+    #[test]
+    fn records_cache_idle_and_usage_distributions() {
+        let (rec, handle) = recorder();
+        let ago = |secs: u64| {
+            Some(prost_types::Timestamp {
+                seconds: (SCRAPE_AT - secs) as i64,
+                nanos: 0,
+            })
+        };
+        let disk = DiskUsageResponse {
+            record: vec![
+                UsageRecord {
+                    size: 100,
+                    last_used_at: ago(60),
+                    usage_count: 12,
+                    ..Default::default()
+                },
+                UsageRecord {
+                    size: 200,
+                    created_at: ago(2 * 86_400),
+                    ..Default::default()
+                },
+                UsageRecord {
+                    size: 400,
+                    created_at: ago(90 * 86_400),
+                    last_used_at: ago(10 * 86_400),
+                    usage_count: 1,
+                    ..Default::default()
+                },
+                UsageRecord {
+                    size: 800,
+                    ..Default::default()
+                },
+            ],
+        };
+        let out = render_with(&rec, &handle, empty_info(), empty_workers(), disk, vec![]);
+        for (le, bytes) in [
+            ("3600", 100),
+            ("86400", 100),
+            ("604800", 300),
+            ("2592000", 700),
+            ("+Inf", 1500),
+        ] {
+            assert!(out.contains(&format!(
+                r#"buildkit_cache_idle_bytes{{builder="default",le="{le}"}} {bytes}"#
+            )));
+        }
+        for (le, records) in [("0", 2), ("1", 3), ("10", 3), ("100", 4), ("+Inf", 4)] {
+            assert!(out.contains(&format!(
+                r#"buildkit_cache_usage_count_records{{builder="default",le="{le}"}} {records}"#
+            )));
+        }
+    }
+
+    #[test]
+    fn vanished_series_drop_out() {
+        let rec = prometheus_builder(Some(Duration::from_millis(100)), &[])
+            .unwrap()
+            .build_recorder();
+        let handle = rec.handle();
+        let worker = |id: &str| WorkerRecord {
+            id: id.into(),
+            ..Default::default()
+        };
+        let usage = |record_type: &str| UsageRecord {
+            size: 10,
+            record_type: record_type.into(),
+            ..Default::default()
+        };
+        let first = render_with(
+            &rec,
+            &handle,
+            empty_info(),
+            ListWorkersResponse {
+                record: vec![worker("w1"), worker("w2")],
+            },
+            DiskUsageResponse {
+                record: vec![usage("regular"), usage("source.local")],
+            },
+            vec![],
+        );
+        assert!(first.contains(r#"record_type="source.local""#));
+        assert!(first.contains(r#"worker_id="w2""#));
+
+        std::thread::sleep(Duration::from_millis(200));
+        let second = render_with(
+            &rec,
+            &handle,
+            empty_info(),
+            ListWorkersResponse {
+                record: vec![worker("w1")],
+            },
+            DiskUsageResponse {
+                record: vec![usage("regular")],
+            },
+            vec![],
+        );
+        assert!(second.contains(
+            r#"buildkit_cache_size_by_type_bytes{builder="default",record_type="regular"} 10"#
+        ));
+        assert!(!second.contains(r#"record_type="source.local""#));
+        assert!(second.contains(r#"worker_id="w1""#));
+        assert!(!second.contains(r#"worker_id="w2""#));
+    }
+
+    #[test]
+    fn idle_builder_drops_out() {
+        let rec = prometheus_builder(Some(Duration::from_millis(100)), &[])
+            .unwrap()
+            .build_recorder();
+        let handle = rec.handle();
+        metrics::with_local_recorder(&rec, || {
+            record_up("old", true);
+            record_up(BUILDER, true);
+        });
+        assert!(handle.render().contains(r#"buildkit_up{builder="old"} 1"#));
+
+        std::thread::sleep(Duration::from_millis(200));
+        metrics::with_local_recorder(&rec, || record_up(BUILDER, true));
+        let out = handle.render();
+        assert!(out.contains(r#"buildkit_up{builder="default"} 1"#));
+        assert!(!out.contains(r#"builder="old""#));
     }
 
     #[test]
@@ -234,7 +964,9 @@ mod tests {
             }],
         };
         let out = render_with(&rec, &handle, empty_info(), empty_workers(), disk, vec![]);
-        assert!(out.contains(r#"buildkit_cache_size_by_type_bytes{record_type="unknown"} 42"#));
+        assert!(out.contains(
+            r#"buildkit_cache_size_by_type_bytes{builder="default",record_type="unknown"} 42"#
+        ));
     }
 
     // -- Build counters --
@@ -244,11 +976,15 @@ mod tests {
         let (rec, handle) = recorder();
         let builds = vec![build_record(None, None, None, 3, 10)];
         let out = render_with(&rec, &handle, empty_info(), empty_workers(), empty_disk(), builds);
-        assert!(out.contains("buildkit_builds_total 1"));
-        assert!(out.contains("buildkit_builds_succeeded_total 1"));
-        assert!(out.contains("buildkit_builds_failed_total 0"));
-        assert!(out.contains("buildkit_builds_cached_steps_total 3"));
-        assert!(out.contains("buildkit_builds_total_steps_total 10"));
+        assert!(out.contains(r#"buildkit_builds_total{builder="default"} 1"#));
+        assert!(out.contains(r#"buildkit_builds_succeeded_total{builder="default"} 1"#));
+        for reason in ["step_failed", "registry", "dockerfile"] {
+            assert!(out.contains(&format!(
+                r#"buildkit_builds_failed_total{{builder="default",reason="{reason}"}} 0"#
+            )));
+        }
+        assert!(out.contains(r#"buildkit_builds_cached_steps_total{builder="default"} 3"#));
+        assert!(out.contains(r#"buildkit_builds_total_steps_total{builder="default"} 10"#));
     }
 
     #[test]
@@ -258,16 +994,76 @@ mod tests {
             None,
             None,
             Some(BuildError {
-                code: 1,
+                code: 2,
                 message: "build failed".into(),
             }),
             0,
             5,
         )];
         let out = render_with(&rec, &handle, empty_info(), empty_workers(), empty_disk(), builds);
-        assert!(out.contains("buildkit_builds_total 1"));
-        assert!(out.contains("buildkit_builds_succeeded_total 0"));
-        assert!(out.contains("buildkit_builds_failed_total 1"));
+        assert!(out.contains(r#"buildkit_builds_total{builder="default"} 1"#));
+        assert!(out.contains(r#"buildkit_builds_succeeded_total{builder="default"} 0"#));
+        assert!(out.contains(r#"buildkit_builds_canceled_total{builder="default"} 0"#));
+        assert!(out.contains(
+            r#"buildkit_builds_failed_total{builder="default",reason="unknown"} 1"#
+        ));
+    }
+
+    #[test]
+    fn canceled_builds_are_not_failures() {
+        let (rec, handle) = recorder();
+        let canceled = |code: i32, message: &str| {
+            build_record(
+                None,
+                None,
+                Some(BuildError {
+                    code,
+                    message: message.into(),
+                }),
+                0,
+                5,
+            )
+        };
+        let builds = vec![
+            canceled(1, "canceled"),
+            canceled(2, "failed to solve: context canceled"),
+        ];
+        let out = render_with(&rec, &handle, empty_info(), empty_workers(), empty_disk(), builds);
+        assert!(out.contains(r#"buildkit_builds_total{builder="default"} 2"#));
+        assert!(out.contains(r#"buildkit_builds_succeeded_total{builder="default"} 0"#));
+        assert!(out.contains(r#"buildkit_builds_canceled_total{builder="default"} 2"#));
+        assert!(out.contains(
+            r#"buildkit_builds_failed_total{builder="default",reason="step_failed"} 0"#
+        ));
+        assert!(!out.contains(r#"reason="cancelled""#));
+    }
+
+    #[test]
+    fn classifies_failed_builds() {
+        let (rec, handle) = recorder();
+        let failed = |message: &str| {
+            build_record(
+                None,
+                None,
+                Some(BuildError {
+                    code: 2,
+                    message: message.into(),
+                }),
+                0,
+                5,
+            )
+        };
+        let builds = vec![
+            failed(r#"process "/bin/sh -c make" did not complete successfully: exit code: 2"#),
+            failed("failed to resolve source metadata for docker.io/library/nope:latest"),
+            failed("something else"),
+        ];
+        let out = render_with(&rec, &handle, empty_info(), empty_workers(), empty_disk(), builds);
+        for reason in ["step_failed", "registry", "unknown"] {
+            assert!(out.contains(&format!(
+                r#"buildkit_builds_failed_total{{builder="default",reason="{reason}"}} 1"#
+            )));
+        }
     }
 
     #[test]
@@ -284,8 +1080,15 @@ mod tests {
             1,
         )];
         let out = render_with(&rec, &handle, empty_info(), empty_workers(), empty_disk(), builds);
-        assert!(out.contains("buildkit_builds_succeeded_total 1"));
-        assert!(out.contains("buildkit_builds_failed_total 0"));
+        assert!(out.contains(r#"buildkit_builds_succeeded_total{builder="default"} 1"#));
+        // Only the zero series of the rule reasons; no gRPC-code reason appears.
+        let failed: Vec<_> = out
+            .lines()
+            .filter(|l| l.starts_with(r#"buildkit_builds_failed_total{builder="default",reason="#))
+            .collect();
+        assert!(!failed.is_empty());
+        assert!(failed.iter().all(|l| l.ends_with(" 0")), "{failed:?}");
+        assert!(!out.contains(r#"reason="unknown""#));
     }
 
     // -- Build duration histogram --
@@ -303,10 +1106,66 @@ mod tests {
         let out = render_with(&rec, &handle, empty_info(), empty_workers(), empty_disk(), builds);
 
         // 45.5s falls in the 60s bucket
-        assert!(out.contains("buildkit_build_duration_seconds_bucket{le=\"30\"} 0"));
-        assert!(out.contains("buildkit_build_duration_seconds_bucket{le=\"60\"} 1"));
-        assert!(out.contains("buildkit_build_duration_seconds_sum 45.5"));
-        assert!(out.contains("buildkit_build_duration_seconds_count 1"));
+        assert!(
+            out.contains(r#"buildkit_build_duration_seconds_bucket{builder="default",le="30"} 0"#)
+        );
+        assert!(
+            out.contains(r#"buildkit_build_duration_seconds_bucket{builder="default",le="60"} 1"#)
+        );
+        assert!(out.contains(r#"buildkit_build_duration_seconds_sum{builder="default"} 45.5"#));
+        assert!(out.contains(r#"buildkit_build_duration_seconds_count{builder="default"} 1"#));
+    }
+
+    #[test]
+    fn records_per_build_step_distributions() {
+        let (rec, handle) = recorder();
+        let mut incomplete = build_record(None, None, None, 0, 40);
+        incomplete.num_completed_steps = 12;
+        let builds = vec![
+            build_record(None, None, None, 9, 10),
+            build_record(None, None, None, 2, 8),
+            incomplete,
+            build_record(None, None, None, 0, 0),
+        ];
+        let out = render_with(&rec, &handle, empty_info(), empty_workers(), empty_disk(), builds);
+
+        // Ratios 0.9, 0.25 and 0; the zero-step build has no ratio.
+        assert!(out.contains(r#"buildkit_build_cache_hit_ratio_bucket{builder="default",le="0"} 1"#));
+        assert!(
+            out.contains(r#"buildkit_build_cache_hit_ratio_bucket{builder="default",le="0.25"} 2"#)
+        );
+        assert!(
+            out.contains(r#"buildkit_build_cache_hit_ratio_bucket{builder="default",le="0.9"} 3"#)
+        );
+        assert!(out.contains(r#"buildkit_build_steps_bucket{builder="default",le="10"} 2"#));
+        assert!(out.contains(r#"buildkit_build_steps_bucket{builder="default",le="50"} 3"#));
+        assert!(out.contains(r#"buildkit_build_steps_count{builder="default"} 3"#));
+        assert!(out.contains(r#"buildkit_builds_incomplete_total{builder="default"} 1"#));
+    }
+
+    #[test]
+    fn splits_build_into_queue_and_execution() {
+        let (rec, handle) = recorder();
+        let build = build_record(
+            Some(ts(1_700_000_000, 0)),
+            Some(ts(1_700_000_100, 0)),
+            None,
+            0,
+            1,
+        );
+        let first_vertex = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_008);
+        metrics::with_local_recorder(&rec, || {
+            record_build_phases(BUILDER, &BuildLabels::default(), &build, first_vertex)
+        });
+        let out = handle.render();
+        assert!(out.contains(r#"buildkit_build_queue_seconds_sum{builder="default"} 8"#));
+        assert!(
+            out.contains(r#"buildkit_build_queue_seconds_bucket{builder="default",le="10"} 1"#)
+        );
+        assert!(out.contains(r#"buildkit_build_execution_seconds_sum{builder="default"} 92"#));
+        assert!(
+            out.contains(r#"buildkit_build_execution_seconds_bucket{builder="default",le="60"} 0"#)
+        );
     }
 
     #[test]
@@ -339,6 +1198,146 @@ mod tests {
         assert!(!out.contains("buildkit_build_duration_seconds"));
     }
 
+    #[test]
+    fn labels_builds_by_frontend() {
+        let (rec, handle) = recorder();
+        let build_labels = BuildLabels::default().with_frontends(&["dockerfile.v0".into()]);
+        let mut custom = build_record(
+            Some(ts(1_700_000_000, 0)),
+            Some(ts(1_700_000_030, 0)),
+            None,
+            0,
+            1,
+        );
+        custom.frontend = "example.com/frontend:v2".into();
+        let builds = [
+            build_record(Some(ts(1_700_000_000, 0)), Some(ts(1_700_000_010, 0)), None, 0, 1),
+            custom,
+        ];
+        metrics::with_local_recorder(&rec, || record_builds(BUILDER, &build_labels, &builds));
+        let out = handle.render();
+        assert!(out.contains(
+            r#"buildkit_builds_total{builder="default",frontend="dockerfile.v0"} 1"#
+        ));
+        assert!(out.contains(r#"buildkit_builds_total{builder="default",frontend="other"} 1"#));
+        assert!(out.contains(
+            r#"buildkit_build_duration_seconds_count{builder="default",frontend="other"} 1"#
+        ));
+    }
+
+    #[test]
+    fn overrides_histogram_buckets() {
+        let buckets =
+            [Buckets::parse("buildkit_build_duration_seconds=10, 600, 3600, 7200, 14400").unwrap()];
+        let rec = prometheus_builder(None, &buckets).unwrap().build_recorder();
+        let handle = rec.handle();
+        let builds = vec![build_record(
+            Some(ts(1_700_000_000, 0)),
+            Some(ts(1_700_005_400, 0)),
+            None,
+            0,
+            1,
+        )];
+        let out = render_with(&rec, &handle, empty_info(), empty_workers(), empty_disk(), builds);
+        assert!(
+            out.contains(r#"buildkit_build_duration_seconds_bucket{builder="default",le="3600"} 0"#)
+        );
+        assert!(
+            out.contains(r#"buildkit_build_duration_seconds_bucket{builder="default",le="7200"} 1"#)
+        );
+        assert!(!out.contains(r#"buildkit_build_duration_seconds_bucket{builder="default",le="60""#));
+        // Other histograms keep their defaults.
+        assert!(out.contains(r#"buildkit_build_steps_bucket{builder="default",le="500"} 1"#));
+    }
+
+    #[test]
+    fn rejects_invalid_buckets() {
+        assert!(Buckets::parse("buildkit_build_duration_seconds").is_err());
+        assert!(Buckets::parse("buildkit_unknown_seconds=1,2").is_err());
+        assert!(Buckets::parse("buildkit_build_duration_seconds=1,x").is_err());
+        assert!(Buckets::parse("buildkit_build_duration_seconds=10,5").is_err());
+        assert!(Buckets::parse("buildkit_build_duration_seconds=1,inf").is_err());
+    }
+
+    // -- In-flight builds --
+
+    #[test]
+    fn records_running_builds() {
+        let (rec, handle) = recorder();
+        let running = vec![
+            build_record(Some(ts(1_700_000_000, 0)), None, None, 0, 0),
+            build_record(Some(ts(1_700_000_900, 0)), None, None, 0, 0),
+        ];
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_003_600);
+        metrics::with_local_recorder(&rec, || record_running(BUILDER, &running, now));
+        let out = handle.render();
+        assert!(out.contains(r#"buildkit_builds_in_progress{builder="default"} 2"#));
+        assert!(
+            out.contains(r#"buildkit_build_oldest_running_age_seconds{builder="default"} 3600"#)
+        );
+    }
+
+    #[test]
+    fn running_gauges_reset_when_idle() {
+        let (rec, handle) = recorder();
+        let running = vec![build_record(Some(ts(1_700_000_000, 0)), None, None, 0, 0)];
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_060);
+        metrics::with_local_recorder(&rec, || {
+            record_running(BUILDER, &running, now);
+            record_running(BUILDER, &[], now);
+        });
+        let out = handle.render();
+        assert!(out.contains(r#"buildkit_builds_in_progress{builder="default"} 0"#));
+        assert!(out.contains(r#"buildkit_build_oldest_running_age_seconds{builder="default"} 0"#));
+    }
+
+    // -- Connection state --
+
+    #[test]
+    fn records_connection_state() {
+        let (rec, handle) = recorder();
+        metrics::with_local_recorder(&rec, || {
+            record_up(BUILDER, true);
+            record_reconnect(BUILDER);
+            record_up(BUILDER, false);
+            record_scrape_success(
+                BUILDER,
+                SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            );
+        });
+        let out = handle.render();
+        assert!(out.contains(r#"buildkit_up{builder="default"} 0"#));
+        assert!(out.contains(r#"buildkit_agent_reconnects_total{builder="default"} 1"#));
+        assert!(out.contains(
+            r#"buildkit_agent_last_scrape_success_timestamp_seconds{builder="default"} 1700000000"#
+        ));
+    }
+
+    #[test]
+    fn records_rpc_duration_and_errors() {
+        let (rec, handle) = recorder();
+        metrics::with_local_recorder(&rec, || {
+            record_rpc(BUILDER, "info", Duration::from_millis(20), None);
+            record_rpc(
+                BUILDER,
+                "disk_usage",
+                Duration::from_secs(3),
+                Some(tonic::Code::DeadlineExceeded),
+            );
+        });
+        let out = handle.render();
+        assert!(out.contains(
+            r#"buildkit_agent_scrape_duration_seconds_bucket{builder="default",rpc="info",le="0.025"} 1"#
+        ));
+        assert!(out.contains(
+            r#"buildkit_agent_scrape_duration_seconds_count{builder="default",rpc="disk_usage"} 1"#
+        ));
+        assert!(out.contains(
+            r#"buildkit_agent_scrape_errors_total{builder="default",rpc="disk_usage",code="DeadlineExceeded"} 1"#
+        ));
+        assert!(!out.contains(r#"buildkit_agent_scrape_errors_total{builder="default",rpc="info""#));
+    }
+
     #[test]
     fn zero_gauges_on_empty_input() {
         let (rec, handle) = recorder();
@@ -350,9 +1349,9 @@ mod tests {
             empty_disk(),
             vec![],
         );
-        assert!(out.contains("buildkit_workers_total 0"));
-        assert!(out.contains("buildkit_cache_records_total 0"));
-        assert!(out.contains("buildkit_cache_size_bytes 0"));
+        assert!(out.contains(r#"buildkit_workers_total{builder="default"} 0"#));
+        assert!(out.contains(r#"buildkit_cache_records_total{builder="default"} 0"#));
+        assert!(out.contains(r#"buildkit_cache_size_bytes{builder="default"} 0"#));
         assert!(!out.contains("buildkit_info"));
     }
 }
@@ -0,0 +1,483 @@
+//! OTLP export: pushes every measurement to an OpenTelemetry Collector over
+//! gRPC or HTTP/protobuf, alongside the Prometheus endpoint. Build spans from
+//! `traces.rs` share the same transport and resources.
+//!
+//! Each builder is its own resource (`service.name`, `host.name`,
+//! `buildkit.builder` plus any configured attributes) and its `builder` label
+//! moves from the data points onto that resource. Counters become cumulative
+//! monotonic sums, gauges stay gauges and histograms keep their Prometheus
+//! bucket bounds.
+
+use crate::generated::otlp::metrics::v1::any_value;
+use crate::generated::otlp::metrics::v1::metric::Data;
+use crate::generated::otlp::metrics::v1::metrics_service_client::MetricsServiceClient;
+use crate::generated::otlp::metrics::v1::number_data_point;
+use crate::generated::otlp::metrics::v1::{
+    AggregationTemporality, AnyValue, ExportMetricsServiceRequest, Gauge, Histogram,
+    HistogramDataPoint, InstrumentationScope, KeyValue, Metric, NumberDataPoint, Resource,
+    ResourceMetrics, ScopeMetrics, Sum,
+};
+use crate::generated::otlp::trace::v1::trace_service_client::TraceServiceClient;
+use crate::generated::otlp::trace::v1::ExportTraceServiceRequest;
+use crate::store::{Sample, Store, Value};
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use prost::Message;
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint};
+
+/// OTLP transport, as in `OTEL_EXPORTER_OTLP_PROTOCOL`: the spec's `grpc` or
+/// `http/protobuf` (also accepted as `http-protobuf`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Protocol {
+    Grpc,
+    #[value(name = "http/protobuf", alias = "http-protobuf")]
+    HttpProtobuf,
+}
+
+/// Parse one `key=value` entry of `OTEL_RESOURCE_ATTRIBUTES`.
+pub fn parse_attribute(spec: &str) -> Result<(String, String)> {
+    match spec.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_string(), value.trim().to_string()))
+        }
+        _ => bail!("resource attribute {spec:?} must be key=value"),
+    }
+}
+
+/// HTTP/1 client for `http://` and `https://` URLs, the latter checked
+/// against the system roots. Also used by the push modes.
+pub type HttpClient = Client<HttpsConnector<HttpConnector>, Full<Bytes>>;
+
+pub fn http_client() -> Result<HttpClient> {
+    let connector = HttpsConnectorBuilder::new()
+        .with_native_roots()
+        .context("loading system root certificates")?
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Ok(Client::builder(TokioExecutor::new()).build(connector))
+}
+
+/// Connection to a collector, shared by the metrics and trace exporters.
+#[derive(Clone)]
+pub enum Transport {
+    Grpc(Channel),
+    Http {
+        client: Box<HttpClient>,
+        endpoint: String,
+    },
+}
+
+impl Transport {
+    /// gRPC endpoints are connected lazily, so a collector that is not up yet
+    /// only fails individual exports. `https://` endpoints use the system roots
+    /// over either protocol.
+    pub fn new(endpoint: &str, protocol: Protocol) -> Result<Self> {
+        let endpoint = endpoint.trim_end_matches('/');
+        match protocol {
+            Protocol::Grpc => {
+                let mut channel = Endpoint::from_shared(endpoint.to_string())
+                    .with_context(|| format!("invalid OTLP endpoint {endpoint}"))?;
+                if endpoint.starts_with("https://") {
+                    channel = channel.tls_config(ClientTlsConfig::new().with_native_roots())?;
+                }
+                Ok(Self::Grpc(channel.connect_lazy()))
+            }
+            Protocol::HttpProtobuf => {
+                if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+                    bail!("OTLP over HTTP needs an http:// or https:// endpoint, got {endpoint}");
+                }
+                let client = Box::new(http_client()?);
+                Ok(Self::Http {
+                    client,
+                    endpoint: endpoint.to_string(),
+                })
+            }
+        }
+    }
+
+    pub async fn export_metrics(&self, request: ExportMetricsServiceRequest) -> Result<()> {
+        match self {
+            Self::Grpc(channel) => {
+                MetricsServiceClient::new(channel.clone())
+                    .export(request)
+                    .await?;
+                Ok(())
+            }
+            Self::Http { .. } => self.post("/v1/metrics", request.encode_to_vec()).await,
+        }
+    }
+
+    pub async fn export_traces(&self, request: ExportTraceServiceRequest) -> Result<()> {
+        match self {
+            Self::Grpc(channel) => {
+                TraceServiceClient::new(channel.clone())
+                    .export(request)
+                    .await?;
+                Ok(())
+            }
+            Self::Http { .. } => self.post("/v1/traces", request.encode_to_vec()).await,
+        }
+    }
+
+    async fn post(&self, path: &str, body: Vec<u8>) -> Result<()> {
+        let Self::Http { client, endpoint } = self else {
+            bail!("not an HTTP transport");
+        };
+        let req = axum::http::Request::post(format!("{endpoint}{path}"))
+            .header(axum::http::header::CONTENT_TYPE, "application/x-protobuf")
+            .body(Full::new(Bytes::from(body)))?;
+        let resp = client.request(req).await?;
+        let status = resp.status();
+        // Drain the body so the connection can be reused.
+        resp.into_body().collect().await?;
+        if !status.is_success() {
+            bail!("collector responded {status}");
+        }
+        Ok(())
+    }
+}
+
+/// Resource attributes shared by every builder (`service.name`, `host.name`
+/// and any configured ones).
+#[derive(Clone)]
+pub struct Resources(Vec<KeyValue>);
+
+impl Resources {
+    pub fn new(service_name: &str, attributes: &[(String, String)]) -> Self {
+        let mut shared = vec![key_value("service.name", service_name)];
+        if let Some(host) = host_name() {
+            shared.push(key_value("host.name", &host));
+        }
+        shared.extend(attributes.iter().map(|(k, v)| key_value(k, v)));
+        Self(shared)
+    }
+
+    /// The resource of one builder's telemetry.
+    pub fn for_builder(&self, builder: Option<&str>) -> Resource {
+        let mut attributes = self.0.clone();
+        attributes.extend(builder.map(|b| key_value("buildkit.builder", b)));
+        Resource { attributes }
+    }
+}
+
+pub fn scope() -> InstrumentationScope {
+    InstrumentationScope {
+        name: env!("CARGO_PKG_NAME").to_string(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+    }
+}
+
+/// Periodically pushes a [`Store`] snapshot to a collector.
+pub struct MetricsExporter {
+    transport: Transport,
+    resources: Resources,
+    /// Start of the cumulative sums and histograms.
+    start: SystemTime,
+    store: Store,
+}
+
+impl MetricsExporter {
+    pub fn new(transport: Transport, resources: Resources, store: Store) -> Self {
+        Self {
+            transport,
+            resources,
+            start: SystemTime::now(),
+            store,
+        }
+    }
+
+    /// Push every `interval` until the process exits. Failed pushes are logged;
+    /// the next one carries the same cumulative values.
+    pub fn spawn(self, interval: Duration) {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                if let Err(e) = self.export(SystemTime::now()).await {
+                    tracing::warn!(err = %e, "OTLP metrics export failed");
+                }
+            }
+        });
+    }
+
+    async fn export(&self, now: SystemTime) -> Result<()> {
+        let request = self.request(&self.store.snapshot(), now);
+        if request.resource_metrics.is_empty() {
+            return Ok(());
+        }
+        self.transport.export_metrics(request).await
+    }
+
+    fn request(&self, samples: &[Sample], now: SystemTime) -> ExportMetricsServiceRequest {
+        let start = unix_nanos(self.start);
+        let time = unix_nanos(now);
+        // Samples are sorted by name, so each builder's series of one metric are adjacent.
+        let mut builders: BTreeMap<Option<&str>, Vec<Metric>> = BTreeMap::new();
+        for sample in samples {
+            let metrics = builders.entry(sample.label("builder")).or_default();
+            push_point(metrics, sample, start, time);
+        }
+        let resource_metrics = builders
+            .into_iter()
+            .map(|(builder, metrics)| ResourceMetrics {
+                resource: Some(self.resources.for_builder(builder)),
+                scope_metrics: vec![ScopeMetrics {
+                    scope: Some(scope()),
+                    metrics,
+                }],
+            })
+            .collect();
+        ExportMetricsServiceRequest { resource_metrics }
+    }
+}
+
+/// Append `sample` to its metric, starting a new one when the name changes.
+fn push_point(metrics: &mut Vec<Metric>, sample: &Sample, start: u64, time: u64) {
+    if metrics.last().is_none_or(|m| m.name != sample.name) {
+        let cumulative = AggregationTemporality::Cumulative as i32;
+        let data = match sample.value {
+            Value::Counter(_) => Data::Sum(Sum {
+                data_points: vec![],
+                aggregation_temporality: cumulative,
+                is_monotonic: true,
+            }),
+            Value::Gauge(_) => Data::Gauge(Gauge::default()),
+            Value::Histogram { .. } => Data::Histogram(Histogram {
+                data_points: vec![],
+                aggregation_temporality: cumulative,
+            }),
+        };
+        metrics.push(Metric {
+            name: sample.name.clone(),
+            data: Some(data),
+            ..Default::default()
+        });
+    }
+    let Some(data) = metrics.last_mut().and_then(|m| m.data.as_mut()) else {
+        return;
+    };
+    let attributes: Vec<_> = sample
+        .labels
+        .iter()
+        .filter(|(k, _)| k != "builder")
+        .map(|(k, v)| key_value(k, v))
+        .collect();
+    match (data, &sample.value) {
+        (Data::Sum(sum), &Value::Counter(value)) => sum.data_points.push(NumberDataPoint {
+            attributes,
+            start_time_unix_nano: start,
+            time_unix_nano: time,
+            value: Some(number_data_point::Value::AsInt(value as i64)),
+        }),
+        (Data::Gauge(gauge), &Value::Gauge(value)) => gauge.data_points.push(NumberDataPoint {
+            attributes,
+            start_time_unix_nano: 0,
+            time_unix_nano: time,
+            value: Some(number_data_point::Value::AsDouble(value)),
+        }),
+        (
+            Data::Histogram(histogram),
+            Value::Histogram {
+                bounds,
+                counts,
+                sum,
+                count,
+                ..
+            },
+        ) => histogram.data_points.push(HistogramDataPoint {
+            attributes,
+            start_time_unix_nano: start,
+            time_unix_nano: time,
+            count: *count,
+            sum: Some(*sum),
+            bucket_counts: counts.clone(),
+            explicit_bounds: bounds.clone(),
+        }),
+        // One name recorded as two kinds; keep the first.
+        _ => {}
+    }
+}
+
+pub fn key_value(key: &str, value: &str) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: Some(AnyValue {
+            value: Some(any_value::Value::StringValue(value.to_string())),
+        }),
+    }
+}
+
+fn host_name() -> Option<String> {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+}
+
+pub fn unix_nanos(t: SystemTime) -> u64 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stand_in;
+
+    fn attribute<'a>(attributes: &'a [KeyValue], key: &str) -> Option<&'a str> {
+        attributes.iter().find(|kv| kv.key == key).and_then(|kv| {
+            match kv.value.as_ref()?.value.as_ref()? {
+                any_value::Value::StringValue(s) => Some(s.as_str()),
+                _ => None,
+            }
+        })
+    }
+
+    #[test]
+    fn parses_resource_attributes() {
+        assert_eq!(
+            parse_attribute("deployment.environment = ci").unwrap(),
+            ("deployment.environment".to_string(), "ci".to_string())
+        );
+        assert!(parse_attribute("novalue").is_err());
+        assert!(parse_attribute("=x").is_err());
+    }
+
+    #[test]
+    fn parses_spec_protocol_names() {
+        use clap::ValueEnum;
+        let parse = |s| Protocol::from_str(s, false);
+        assert_eq!(parse("grpc"), Ok(Protocol::Grpc));
+        assert_eq!(parse("http/protobuf"), Ok(Protocol::HttpProtobuf));
+        assert_eq!(parse("http-protobuf"), Ok(Protocol::HttpProtobuf));
+        assert!(parse("http/json").is_err());
+    }
+
+    #[test]
+    fn http_endpoint_must_be_http_or_https() {
+        assert!(Transport::new("https://collector:4318", Protocol::HttpProtobuf).is_ok());
+        assert!(Transport::new("collector:4318", Protocol::HttpProtobuf).is_err());
+    }
+
+    #[tokio::test]
+    async fn exports_one_resource_per_builder() {
+        let store = Store::new(None, &[], false);
+        metrics::with_local_recorder(&store, || {
+            metrics::counter!("buildkit_builds_succeeded_total", "builder" => "a").increment(3);
+            metrics::counter!("buildkit_builds_succeeded_total", "builder" => "b").increment(1);
+            metrics::gauge!("buildkit_workers_total", "builder" => "a").set(2.0);
+            metrics::histogram!("buildkit_build_steps", "builder" => "a", "frontend" => "dockerfile.v0")
+                .record(7.0);
+        });
+        let (endpoint, mut received) = stand_in::http().await;
+        let attributes = [("deployment.environment".to_string(), "ci".to_string())];
+        let transport = Transport::new(&endpoint, Protocol::HttpProtobuf).unwrap();
+        let exporter = MetricsExporter::new(transport, Resources::new("agent", &attributes), store);
+        exporter.export(SystemTime::now()).await.unwrap();
+
+        let received = received.recv().await.unwrap();
+        assert_eq!(received.path, "/v1/metrics");
+        let request = ExportMetricsServiceRequest::decode(received.body).unwrap();
+        assert_eq!(request.resource_metrics.len(), 2);
+        let a = &request.resource_metrics[0];
+        let resource = &a.resource.as_ref().unwrap().attributes;
+        assert_eq!(attribute(resource, "service.name"), Some("agent"));
+        assert_eq!(attribute(resource, "buildkit.builder"), Some("a"));
+        assert_eq!(attribute(resource, "deployment.environment"), Some("ci"));
+
+        let metrics = &a.scope_metrics[0].metrics;
+        let names: Vec<_> = metrics.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "buildkit_build_steps",
+                "buildkit_builds_succeeded_total",
+                "buildkit_workers_total"
+            ]
+        );
+        let Some(Data::Histogram(steps)) = &metrics[0].data else {
+            panic!("steps is not a histogram");
+        };
+        let point = &steps.data_points[0];
+        assert_eq!(
+            attribute(&point.attributes, "frontend"),
+            Some("dockerfile.v0")
+        );
+        assert_eq!(attribute(&point.attributes, "builder"), None);
+        assert_eq!((point.count, point.sum), (1, Some(7.0)));
+        assert_eq!(point.bucket_counts.len(), point.explicit_bounds.len() + 1);
+        let Some(Data::Sum(succeeded)) = &metrics[1].data else {
+            panic!("succeeded is not a sum");
+        };
+        assert!(succeeded.is_monotonic);
+        assert_eq!(
+            succeeded.data_points[0].value,
+            Some(number_data_point::Value::AsInt(3))
+        );
+        let Some(Data::Gauge(workers)) = &metrics[2].data else {
+            panic!("workers is not a gauge");
+        };
+        assert_eq!(
+            workers.data_points[0].value,
+            Some(number_data_point::Value::AsDouble(2.0))
+        );
+    }
+
+    #[tokio::test]
+    async fn posts_traces_to_their_own_path() {
+        let (endpoint, mut received) = stand_in::http().await;
+        let transport = Transport::new(&endpoint, Protocol::HttpProtobuf).unwrap();
+        let request = ExportTraceServiceRequest {
+            resource_spans: vec![Default::default()],
+        };
+        transport.export_traces(request.clone()).await.unwrap();
+        let received = received.recv().await.unwrap();
+        assert_eq!(received.path, "/v1/traces");
+        assert_eq!(
+            ExportTraceServiceRequest::decode(received.body).unwrap(),
+            request
+        );
+    }
+
+    #[tokio::test]
+    async fn exports_over_grpc() {
+        let (endpoint, mut received) = stand_in::grpc().await;
+        let transport = Transport::new(&endpoint, Protocol::Grpc).unwrap();
+        let metrics = ExportMetricsServiceRequest {
+            resource_metrics: vec![Default::default()],
+        };
+        transport.export_metrics(metrics.clone()).await.unwrap();
+        let call = received.recv().await.unwrap();
+        assert_eq!(
+            call.path,
+            "/opentelemetry.proto.collector.metrics.v1.MetricsService/Export"
+        );
+        assert_eq!(
+            ExportMetricsServiceRequest::decode(call.grpc_message()).unwrap(),
+            metrics
+        );
+
+        let traces = ExportTraceServiceRequest {
+            resource_spans: vec![Default::default()],
+        };
+        transport.export_traces(traces.clone()).await.unwrap();
+        let call = received.recv().await.unwrap();
+        assert_eq!(
+            call.path,
+            "/opentelemetry.proto.collector.trace.v1.TraceService/Export"
+        );
+        assert_eq!(
+            ExportTraceServiceRequest::decode(call.grpc_message()).unwrap(),
+            traces
+        );
+    }
+}
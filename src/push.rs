@@ -0,0 +1,350 @@
+//! Push modes for hosts that may disappear before Prometheus scrapes them:
+//! the rendered `/metrics` exposition is sent to a Pushgateway or converted to
+//! a Prometheus remote-write request, on an interval and once more on shutdown.
+
+use crate::generated::prometheus::{Label, Sample, TimeSeries, WriteRequest};
+use crate::labels::is_label_name;
+use crate::otlp::{http_client, HttpClient};
+
+use anyhow::{bail, Result};
+use base64::engine::general_purpose::URL_SAFE;
+use base64::Engine;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use metrics_exporter_prometheus::PrometheusHandle;
+use prost::Message;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// Where to push.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Destination {
+    /// Full grouping URL, `<base>/metrics/job/<job>/<label>/<value>...`.
+    Pushgateway(String),
+    RemoteWrite {
+        url: String,
+        /// `job` plus the grouping labels, added to series that lack them.
+        labels: Vec<(String, String)>,
+    },
+}
+
+impl Destination {
+    /// A Pushgateway group: every push replaces the metrics of `job` and `grouping`.
+    pub fn pushgateway(url: &str, job: &str, grouping: &[(String, String)]) -> Result<Self> {
+        let mut url = format!("{}/metrics/{}", http_base(url)?, path_segment("job", job));
+        for (name, value) in grouping {
+            url.push('/');
+            url.push_str(&path_segment(name, value));
+        }
+        Ok(Self::Pushgateway(url))
+    }
+
+    pub fn remote_write(url: &str, job: &str, grouping: &[(String, String)]) -> Result<Self> {
+        let mut labels = vec![("job".to_string(), job.to_string())];
+        labels.extend(grouping.iter().cloned());
+        Ok(Self::RemoteWrite {
+            url: http_base(url)?.to_string(),
+            labels,
+        })
+    }
+}
+
+/// Parse one `name=value` grouping label.
+pub fn parse_grouping(spec: &str) -> Result<(String, String)> {
+    let Some((name, value)) = spec.split_once('=') else {
+        bail!("grouping label {spec:?} must be name=value");
+    };
+    if !is_label_name(name) || name == "job" {
+        bail!("invalid grouping label name {name:?}");
+    }
+    Ok((name.to_string(), value.to_string()))
+}
+
+fn http_base(url: &str) -> Result<&str> {
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        bail!("push URL must be http:// or https://, got {url}");
+    }
+    Ok(url.trim_end_matches('/'))
+}
+
+/// `name/value`, switching to the base64 form for values a path cannot carry
+/// as-is (slashes, empty values, anything outside `[A-Za-z0-9._-]`).
+fn path_segment(name: &str, value: &str) -> String {
+    let plain = !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'_' | b'-'));
+    if plain {
+        format!("{name}/{value}")
+    } else if value.is_empty() {
+        format!("{name}@base64/=")
+    } else {
+        format!("{name}@base64/{}", URL_SAFE.encode(value))
+    }
+}
+
+/// Pushes the recorder's current state to one destination.
+pub struct Pusher {
+    client: HttpClient,
+    destination: Destination,
+    handle: PrometheusHandle,
+}
+
+impl Pusher {
+    /// `https://` destinations use the system roots.
+    pub fn new(destination: Destination, handle: PrometheusHandle) -> Result<Self> {
+        Ok(Self {
+            client: http_client()?,
+            destination,
+            handle,
+        })
+    }
+
+    /// Push every `interval`, as [`MetricsExporter::spawn`] does.
+    ///
+    /// [`MetricsExporter::spawn`]: crate::otlp::MetricsExporter::spawn
+    pub fn spawn(self: Arc<Self>, interval: Duration) {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                if let Err(e) = self.push().await {
+                    tracing::warn!(destination = ?self.destination, err = %e, "metrics push failed");
+                }
+            }
+        });
+    }
+
+    pub async fn push(&self) -> Result<()> {
+        let exposition = self.handle.render();
+        let req = match &self.destination {
+            Destination::Pushgateway(url) => axum::http::Request::put(url.as_str())
+                .header(
+                    axum::http::header::CONTENT_TYPE,
+                    "text/plain; version=0.0.4",
+                )
+                .body(Full::new(Bytes::from(exposition)))?,
+            Destination::RemoteWrite { url, labels } => {
+                let request = write_request(&exposition, labels, SystemTime::now());
+                // Remote write takes the snappy block format, not the framed one.
+                let body = snap::raw::Encoder::new().compress_vec(&request.encode_to_vec())?;
+                axum::http::Request::post(url.as_str())
+                    .header(axum::http::header::CONTENT_TYPE, "application/x-protobuf")
+                    .header(axum::http::header::CONTENT_ENCODING, "snappy")
+                    .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+                    .body(Full::new(Bytes::from(body)))?
+            }
+        };
+        let resp = self.client.request(req).await?;
+        let status = resp.status();
+        let body = resp.into_body().collect().await?.to_bytes();
+        if !status.is_success() {
+            bail!(
+                "push responded {status}: {}",
+                String::from_utf8_lossy(&body).trim()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// One remote-write series per exposition line, stamped `now`. `labels` are
+/// added to series that do not carry them already.
+fn write_request(exposition: &str, labels: &[(String, String)], now: SystemTime) -> WriteRequest {
+    let timestamp = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64);
+    let timeseries = exposition
+        .lines()
+        .filter_map(parse_sample)
+        .map(|(mut series, value)| {
+            for (name, value) in labels {
+                if !series.iter().any(|(n, _)| n == name) {
+                    series.push((name.clone(), value.clone()));
+                }
+            }
+            series.sort();
+            TimeSeries {
+                labels: series
+                    .into_iter()
+                    .map(|(name, value)| Label { name, value })
+                    .collect(),
+                samples: vec![Sample { value, timestamp }],
+            }
+        })
+        .collect();
+    WriteRequest { timeseries }
+}
+
+/// Parse one text exposition sample line into its labels (with `__name__`)
+/// and value. Comments, blank and malformed lines yield `None`.
+fn parse_sample(line: &str) -> Option<(Vec<(String, String)>, f64)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let name_end = line.find(['{', ' '])?;
+    let mut labels = vec![("__name__".to_string(), line[..name_end].to_string())];
+    let mut rest = &line[name_end..];
+    if let Some(mut inner) = rest.strip_prefix('{') {
+        loop {
+            inner = inner.trim_start_matches([',', ' ']);
+            if let Some(after) = inner.strip_prefix('}') {
+                rest = after;
+                break;
+            }
+            let (name, after) = inner.split_once("=\"")?;
+            let mut value = String::new();
+            let mut chars = after.char_indices();
+            let end = loop {
+                match chars.next()? {
+                    (i, '"') => break i,
+                    (_, '\\') => match chars.next()?.1 {
+                        'n' => value.push('\n'),
+                        c => value.push(c),
+                    },
+                    (_, c) => value.push(c),
+                }
+            };
+            labels.push((name.trim().to_string(), value));
+            inner = &after[end + 1..];
+        }
+    }
+    let value = rest.split_whitespace().next()?.parse().ok()?;
+    Some((labels, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stand_in;
+
+    #[test]
+    fn pushgateway_url_encodes_grouping_values() {
+        let grouping = [
+            ("builder".to_string(), "pool-0".to_string()),
+            ("instance".to_string(), "ci/runner 1".to_string()),
+            ("empty".to_string(), String::new()),
+        ];
+        let dest = Destination::pushgateway("http://pgw:9091/", "buildkit", &grouping).unwrap();
+        assert_eq!(
+            dest,
+            Destination::Pushgateway(
+                "http://pgw:9091/metrics/job/buildkit/builder/pool-0/instance@base64/Y2kvcnVubmVyIDE=/empty@base64/="
+                    .to_string()
+            )
+        );
+        assert!(Destination::pushgateway("https://pgw", "buildkit", &[]).is_ok());
+        assert!(Destination::pushgateway("pgw:9091", "buildkit", &[]).is_err());
+        assert!(parse_grouping("instance=ci-1").is_ok());
+        assert!(parse_grouping("job=other").is_err());
+        assert!(parse_grouping("bad-name=x").is_err());
+    }
+
+    #[test]
+    fn parses_exposition_lines() {
+        assert_eq!(parse_sample("# TYPE buildkit_up gauge"), None);
+        assert_eq!(
+            parse_sample("buildkit_workers_total 2"),
+            Some((
+                vec![("__name__".into(), "buildkit_workers_total".into())],
+                2.0
+            ))
+        );
+        let (labels, value) = parse_sample(
+            r#"buildkit_build_duration_seconds_bucket{builder="a \"b\"",le="+Inf"} 3"#,
+        )
+        .unwrap();
+        assert_eq!(value, 3.0);
+        assert_eq!(labels[1], ("builder".into(), "a \"b\"".into()));
+        assert_eq!(labels[2], ("le".into(), "+Inf".into()));
+    }
+
+    #[test]
+    fn write_request_adds_job_and_sorts_labels() {
+        let exposition = "# TYPE buildkit_builds_succeeded_total counter\n\
+                          buildkit_builds_succeeded_total{builder=\"a\",job=\"own\"} 4\n";
+        let labels = [
+            ("job".to_string(), "buildkit".to_string()),
+            ("env".to_string(), "ci".to_string()),
+        ];
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let request = write_request(exposition, &labels, now);
+        assert_eq!(request.timeseries.len(), 1);
+        let series = &request.timeseries[0];
+        let names: Vec<_> = series
+            .labels
+            .iter()
+            .map(|l| (l.name.as_str(), l.value.as_str()))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("__name__", "buildkit_builds_succeeded_total"),
+                ("builder", "a"),
+                ("env", "ci"),
+                ("job", "own")
+            ]
+        );
+        assert_eq!(
+            series.samples,
+            [Sample {
+                value: 4.0,
+                timestamp: 1_700_000_000_000
+            }]
+        );
+    }
+
+    fn handle() -> PrometheusHandle {
+        let recorder = metrics_exporter_prometheus::PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        metrics::with_local_recorder(&recorder, || {
+            metrics::counter!("buildkit_builds_succeeded_total", "builder" => "a").increment(4);
+        });
+        handle
+    }
+
+    #[tokio::test]
+    async fn pushes_to_pushgateway_group() {
+        let (url, mut received) = stand_in::http().await;
+        let destination = Destination::pushgateway(&url, "buildkit", &[]).unwrap();
+        Pusher::new(destination, handle())
+            .unwrap()
+            .push()
+            .await
+            .unwrap();
+
+        let req = received.recv().await.unwrap();
+        assert_eq!(req.method, axum::http::Method::PUT);
+        assert_eq!(req.path, "/metrics/job/buildkit");
+        assert_eq!(req.headers["content-type"], "text/plain; version=0.0.4");
+        let body = String::from_utf8(req.body.to_vec()).unwrap();
+        assert!(body.contains("buildkit_builds_succeeded_total{builder=\"a\"} 4"));
+    }
+
+    #[tokio::test]
+    async fn pushes_snappy_compressed_remote_write() {
+        let (url, mut received) = stand_in::http().await;
+        let destination =
+            Destination::remote_write(&format!("{url}/api/v1/write"), "buildkit", &[]).unwrap();
+        Pusher::new(destination, handle())
+            .unwrap()
+            .push()
+            .await
+            .unwrap();
+
+        let req = received.recv().await.unwrap();
+        assert_eq!(req.method, axum::http::Method::POST);
+        assert_eq!(req.path, "/api/v1/write");
+        assert_eq!(req.headers["content-type"], "application/x-protobuf");
+        assert_eq!(req.headers["content-encoding"], "snappy");
+        assert_eq!(req.headers["x-prometheus-remote-write-version"], "0.1.0");
+        let body = snap::raw::Decoder::new().decompress_vec(&req.body).unwrap();
+        let request = WriteRequest::decode(&body[..]).unwrap();
+        assert_eq!(request.timeseries.len(), 1);
+        assert_eq!(request.timeseries[0].samples[0].value, 4.0);
+        assert!(request.timeseries[0].labels.contains(&Label {
+            name: "job".into(),
+            value: "buildkit".into()
+        }));
+    }
+}
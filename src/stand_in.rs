@@ -0,0 +1,74 @@
+//! Stand-in servers for tests of the outputs that send over HTTP or gRPC.
+
+use axum::http::{HeaderMap, Method, Uri};
+use axum::response::{IntoResponse, Response};
+use bytes::Bytes;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+/// One request as the stand-in received it.
+pub struct Received {
+    pub method: Method,
+    pub path: String,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl Received {
+    /// The message of a unary gRPC call, without its 5-byte prefix.
+    pub fn grpc_message(&self) -> &[u8] {
+        &self.body[5..]
+    }
+}
+
+/// HTTP/1 server on a free local port that answers every request with an
+/// empty 200 and forwards it. Returns its `http://` base URL.
+pub async fn http() -> (String, mpsc::UnboundedReceiver<Received>) {
+    let (app, rx) = forwarding(|| "".into_response());
+    let (listener, url) = bind().await;
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    (url, rx)
+}
+
+/// Like [`http`], over HTTP/2, answering every unary gRPC call with an empty
+/// message.
+pub async fn grpc() -> (String, mpsc::UnboundedReceiver<Received>) {
+    let (app, rx) = forwarding(|| {
+        let headers = [("content-type", "application/grpc"), ("grpc-status", "0")];
+        (headers, Bytes::from_static(&[0; 5])).into_response()
+    });
+    let (listener, url) = bind().await;
+    let incoming =
+        tonic::transport::server::TcpIncoming::from_listener(listener, true, None).unwrap();
+    tokio::spawn(
+        tonic::transport::Server::builder()
+            .add_routes(app.into())
+            .serve_with_incoming(incoming),
+    );
+    (url, rx)
+}
+
+async fn bind() -> (TcpListener, String) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    (listener, url)
+}
+
+fn forwarding(respond: fn() -> Response) -> (axum::Router, mpsc::UnboundedReceiver<Received>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let app = axum::Router::new().fallback(move |method, uri: Uri, headers, body: Bytes| {
+        let tx = tx.clone();
+        async move {
+            let path = uri.path().to_string();
+            tx.send(Received {
+                method,
+                path,
+                headers,
+                body,
+            })
+            .unwrap();
+            respond()
+        }
+    });
+    (app, rx)
+}
@@ -0,0 +1,393 @@
+//! StatsD / DogStatsD output: every measurement is also sent as a UDP datagram,
+//! for hosts watched by a Datadog (or other StatsD) agent instead of Prometheus.
+//!
+//! Counters send their increments, gauges their current value and histograms
+//! each observation (`d` distributions for DogStatsD, `h` for plain StatsD).
+//! Labels become DogStatsD tags; plain StatsD has no tags, so label values are
+//! appended to the name Graphite-style instead (`name.value1.value2`), keeping
+//! series that differ only by label apart.
+
+use anyhow::{Context, Result};
+use metrics::{
+    Counter, CounterFn, Gauge, GaugeFn, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder,
+    SharedString, Unit,
+};
+use metrics_util::registry::{Registry, Storage};
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Wire dialect.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Flavor {
+    Dogstatsd,
+    Statsd,
+}
+
+/// Recorder sending to one StatsD server. Cheap to clone.
+///
+/// Handles are kept per key so a series' running value (counter total, gauge
+/// value) and its tag string survive across `counter!`/`gauge!` calls.
+#[derive(Clone)]
+pub struct Statsd(Arc<Registry<Key, Handles>>);
+
+struct Sink {
+    socket: UdpSocket,
+    flavor: Flavor,
+    /// Tags added to every metric, already joined (`env:ci,team:infra`).
+    tags: String,
+}
+
+impl Sink {
+    /// Send `lines` (`name:value|type` without tags) in one datagram.
+    /// Delivery is best effort, like StatsD itself.
+    fn send(&self, lines: &[String], tags: &str) {
+        let mut datagram = String::new();
+        for line in lines {
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            datagram.push_str(line);
+            if !tags.is_empty() {
+                datagram.push_str("|#");
+                datagram.push_str(tags);
+            }
+        }
+        if let Err(e) = self.socket.send(datagram.as_bytes()) {
+            tracing::debug!(err = %e, "statsd send failed");
+        }
+    }
+}
+
+impl Statsd {
+    /// `addr` is `host:port`, resolved once at startup. `tags` are `key:value`
+    /// pairs added to every DogStatsD metric.
+    pub fn connect(addr: &str, flavor: Flavor, tags: &[String]) -> Result<Self> {
+        let target = addr
+            .to_socket_addrs()
+            .with_context(|| format!("resolve statsd address {addr}"))?
+            .next()
+            .with_context(|| format!("statsd address {addr} did not resolve"))?;
+        let local = if target.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(target)?;
+        socket.set_nonblocking(true)?;
+        let tags = tags
+            .iter()
+            .map(|t| sanitize(t))
+            .collect::<Vec<_>>()
+            .join(",");
+        let sink = Arc::new(Sink {
+            socket,
+            flavor,
+            tags,
+        });
+        Ok(Self(Arc::new(Registry::new(Handles(sink)))))
+    }
+}
+
+/// Registry storage creating a [`Handle`] per series.
+struct Handles(Arc<Sink>);
+
+impl Handles {
+    fn handle(&self, key: &Key) -> Arc<Handle> {
+        let mut name = key.name().to_string();
+        let mut tags = Vec::new();
+        match self.0.flavor {
+            Flavor::Dogstatsd => {
+                tags.extend(
+                    key.labels()
+                        .map(|l| format!("{}:{}", sanitize(l.key()), sanitize(l.value()))),
+                );
+                if !self.0.tags.is_empty() {
+                    tags.push(self.0.tags.clone());
+                }
+            }
+            Flavor::Statsd => {
+                for label in key.labels() {
+                    name.push('.');
+                    name.push_str(&path_component(label.value()));
+                }
+            }
+        }
+        Arc::new(Handle {
+            sink: Arc::clone(&self.0),
+            name,
+            tags: tags.join(","),
+            value: AtomicU64::new(0),
+        })
+    }
+}
+
+impl Storage<Key> for Handles {
+    type Counter = Arc<Handle>;
+    type Gauge = Arc<Handle>;
+    type Histogram = Arc<Handle>;
+
+    fn counter(&self, key: &Key) -> Arc<Handle> {
+        self.handle(key)
+    }
+
+    fn gauge(&self, key: &Key) -> Arc<Handle> {
+        self.handle(key)
+    }
+
+    fn histogram(&self, key: &Key) -> Arc<Handle> {
+        self.handle(key)
+    }
+}
+
+/// Tag text cannot carry the protocol's separators.
+fn sanitize(s: &str) -> String {
+    s.replace([',', '|', '#', '\n'], "_")
+}
+
+/// A label value as one dot-separated name component: anything but
+/// `[A-Za-z0-9_-]` (including `.` and `:`) becomes `_`.
+fn path_component(value: &str) -> String {
+    if value.is_empty() {
+        return "_".to_string();
+    }
+    value
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// One series. `value` is the counter total or the gauge's bits, so absolute
+/// counters send a delta and relative gauge updates send the new value.
+struct Handle {
+    sink: Arc<Sink>,
+    name: String,
+    tags: String,
+    value: AtomicU64,
+}
+
+impl Handle {
+    fn send(&self, value: impl std::fmt::Display, kind: &str) {
+        self.sink
+            .send(&[format!("{}:{value}|{kind}", self.name)], &self.tags);
+    }
+
+    fn set_gauge(&self, value: f64) {
+        if !value.is_finite() {
+            return;
+        }
+        if value < 0.0 && self.sink.flavor == Flavor::Statsd {
+            // A leading `-` is a decrement in plain StatsD; reset to zero first.
+            let lines = [
+                format!("{}:0|g", self.name),
+                format!("{}:{value}|g", self.name),
+            ];
+            self.sink.send(&lines, &self.tags);
+        } else {
+            self.send(value, "g");
+        }
+    }
+
+    fn update_gauge(&self, f: impl Fn(f64) -> f64) {
+        let mut current = self.value.load(Ordering::Acquire);
+        loop {
+            let next = f(f64::from_bits(current)).to_bits();
+            match self
+                .value
+                .compare_exchange(current, next, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => return self.set_gauge(f64::from_bits(next)),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+}
+
+impl CounterFn for Handle {
+    fn increment(&self, value: u64) {
+        self.value.fetch_add(value, Ordering::AcqRel);
+        if value > 0 {
+            self.send(value, "c");
+        }
+    }
+
+    fn absolute(&self, value: u64) {
+        let previous = self.value.fetch_max(value, Ordering::AcqRel);
+        if value > previous {
+            self.send(value - previous, "c");
+        }
+    }
+}
+
+impl GaugeFn for Handle {
+    fn increment(&self, value: f64) {
+        self.update_gauge(|v| v + value);
+    }
+
+    fn decrement(&self, value: f64) {
+        self.update_gauge(|v| v - value);
+    }
+
+    fn set(&self, value: f64) {
+        self.value.store(value.to_bits(), Ordering::Release);
+        self.set_gauge(value);
+    }
+}
+
+impl HistogramFn for Handle {
+    fn record(&self, value: f64) {
+        if value.is_finite() {
+            let kind = match self.sink.flavor {
+                Flavor::Dogstatsd => "d",
+                Flavor::Statsd => "h",
+            };
+            self.send(value, kind);
+        }
+    }
+}
+
+impl Recorder for Statsd {
+    fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+    fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+    fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+    fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+        Counter::from_arc(self.0.get_or_create_counter(key, Arc::clone))
+    }
+
+    fn register_gauge(&self, key: &Key, _: &Metadata<'_>) -> Gauge {
+        Gauge::from_arc(self.0.get_or_create_gauge(key, Arc::clone))
+    }
+
+    fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+        Histogram::from_arc(self.0.get_or_create_histogram(key, Arc::clone))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn server() -> (UdpSocket, String) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+        (socket, addr)
+    }
+
+    fn recv(socket: &UdpSocket) -> String {
+        let mut buf = [0; 1024];
+        let n = socket.recv(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..n]).into_owned()
+    }
+
+    #[test]
+    fn dogstatsd_sends_labels_as_tags() {
+        let (socket, addr) = server();
+        let statsd = Statsd::connect(&addr, Flavor::Dogstatsd, &["env:ci".to_string()]).unwrap();
+        metrics::with_local_recorder(&statsd, || {
+            let builds = metrics::counter!("buildkit_builds_total", "builder" => "pool-0");
+            builds.increment(0);
+            builds.increment(2);
+            metrics::gauge!("buildkit_workers_total", "builder" => "pool-0").set(3.0);
+            metrics::histogram!("buildkit_build_duration_seconds", "builder" => "a|b").record(1.5);
+        });
+        assert_eq!(
+            recv(&socket),
+            "buildkit_builds_total:2|c|#builder:pool-0,env:ci"
+        );
+        assert_eq!(
+            recv(&socket),
+            "buildkit_workers_total:3|g|#builder:pool-0,env:ci"
+        );
+        assert_eq!(
+            recv(&socket),
+            "buildkit_build_duration_seconds:1.5|d|#builder:a_b,env:ci"
+        );
+    }
+
+    #[test]
+    fn plain_statsd_folds_labels_into_name() {
+        let (socket, addr) = server();
+        let statsd = Statsd::connect(&addr, Flavor::Statsd, &["env:ci".to_string()]).unwrap();
+        metrics::with_local_recorder(&statsd, || {
+            let gauge = metrics::gauge!("buildkit_builds_in_progress", "builder" => "pool-0");
+            gauge.increment(2.0);
+            gauge.decrement(3.0);
+            metrics::histogram!("buildkit_build_steps", "builder" => "unix:///run/b.sock")
+                .record(12.0);
+        });
+        assert_eq!(recv(&socket), "buildkit_builds_in_progress.pool-0:2|g");
+        assert_eq!(
+            recv(&socket),
+            "buildkit_builds_in_progress.pool-0:0|g\nbuildkit_builds_in_progress.pool-0:-1|g"
+        );
+        assert_eq!(
+            recv(&socket),
+            "buildkit_build_steps.unix____run_b_sock:12|h"
+        );
+    }
+
+    #[test]
+    fn plain_statsd_keeps_label_sets_apart() {
+        let (socket, addr) = server();
+        let statsd = Statsd::connect(&addr, Flavor::Statsd, &[]).unwrap();
+        metrics::with_local_recorder(&statsd, || {
+            for (builder, record_type, bytes) in [
+                ("a", "regular", 10.0),
+                ("a", "source.local", 20.0),
+                ("b", "regular", 30.0),
+            ] {
+                metrics::gauge!(
+                    "buildkit_cache_size_by_type_bytes",
+                    "builder" => builder,
+                    "type" => record_type
+                )
+                .set(bytes);
+            }
+        });
+        assert_eq!(
+            recv(&socket),
+            "buildkit_cache_size_by_type_bytes.a.regular:10|g"
+        );
+        assert_eq!(
+            recv(&socket),
+            "buildkit_cache_size_by_type_bytes.a.source_local:20|g"
+        );
+        assert_eq!(
+            recv(&socket),
+            "buildkit_cache_size_by_type_bytes.b.regular:30|g"
+        );
+    }
+
+    #[test]
+    fn series_keep_their_value_across_macro_calls() {
+        let (socket, addr) = server();
+        let statsd = Statsd::connect(&addr, Flavor::Statsd, &[]).unwrap();
+        metrics::with_local_recorder(&statsd, || {
+            for _ in 0..2 {
+                metrics::gauge!("buildkit_builds_in_progress", "builder" => "pool-0")
+                    .increment(1.0);
+            }
+            for total in [3, 5] {
+                metrics::counter!("buildkit_builds_total", "builder" => "pool-0").absolute(total);
+            }
+        });
+        assert_eq!(recv(&socket), "buildkit_builds_in_progress.pool-0:1|g");
+        assert_eq!(recv(&socket), "buildkit_builds_in_progress.pool-0:2|g");
+        assert_eq!(recv(&socket), "buildkit_builds_total.pool-0:3|c");
+        assert_eq!(recv(&socket), "buildkit_builds_total.pool-0:2|c");
+    }
+}
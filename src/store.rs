@@ -0,0 +1,355 @@
+//! In-process copy of every measurement, for exporters that push rather than
+//! serve `/metrics`.
+//!
+//! [`Store`] is installed next to the Prometheus recorder behind a fanout, so
+//! it sees exactly what `metrics.rs` records. Histograms use the same bucket
+//! bounds as `/metrics` and gauges go stale after the same idle timeout. With
+//! native histograms on, every histogram also keeps sparse exponential buckets.
+
+use crate::metrics::{histogram_bounds, Buckets};
+
+use metrics::{Counter, Gauge, Histogram, Key, KeyName, Metadata, Recorder, SharedString, Unit};
+use metrics_util::registry::{GenerationalAtomicStorage, Recency, Registry};
+use metrics_util::MetricKindMask;
+use quanta::Clock;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// One series at the time of a [`Store::snapshot`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub name: String,
+    pub labels: Vec<(String, String)>,
+    pub value: Value,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Counter(u64),
+    Gauge(f64),
+    /// Cumulative since the agent started. `counts[i]` is the number of
+    /// observations in `(bounds[i - 1], bounds[i]]`; the last entry counts those
+    /// above every bound.
+    Histogram {
+        bounds: Vec<f64>,
+        counts: Vec<u64>,
+        sum: f64,
+        count: u64,
+        native: Option<Sparse>,
+    },
+}
+
+/// Most populated native buckets per histogram; beyond it the resolution is
+/// halved, as Prometheus client libraries do.
+const MAX_NATIVE_BUCKETS: usize = 160;
+/// Starting native resolution: each bucket is ~9% wider than the previous.
+const NATIVE_SCHEMA: i32 = 3;
+/// Observations at most this far from zero land in the zero bucket.
+pub const NATIVE_ZERO_THRESHOLD: f64 = 2.938_735_877_055_719e-39;
+
+/// Native (sparse) histogram buckets. Bucket `i` counts observations `v` with
+/// `base^(i-1) < |v| <= base^i`, `base = 2^(2^-schema)`; only populated
+/// buckets are kept.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sparse {
+    pub schema: i32,
+    pub zero_count: u64,
+    pub positive: BTreeMap<i32, u64>,
+    pub negative: BTreeMap<i32, u64>,
+}
+
+impl Sparse {
+    fn new() -> Self {
+        Self {
+            schema: NATIVE_SCHEMA,
+            zero_count: 0,
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+        }
+    }
+
+    fn record(&mut self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        if value.abs() <= NATIVE_ZERO_THRESHOLD {
+            self.zero_count += 1;
+            return;
+        }
+        let index = bucket_index(value.abs(), self.schema);
+        let buckets = if value > 0.0 {
+            &mut self.positive
+        } else {
+            &mut self.negative
+        };
+        *buckets.entry(index).or_default() += 1;
+        while self.positive.len() + self.negative.len() > MAX_NATIVE_BUCKETS && self.schema > -4 {
+            self.schema -= 1;
+            self.positive = merge_halves(&self.positive);
+            self.negative = merge_halves(&self.negative);
+        }
+    }
+}
+
+/// Index of the bucket holding `value` (> 0) at `schema`.
+fn bucket_index(value: f64, schema: i32) -> i32 {
+    if value.is_infinite() {
+        return i32::MAX;
+    }
+    let index = (value.log2() * 2f64.powi(schema)).ceil();
+    index.clamp(i32::MIN as f64, i32::MAX as f64) as i32
+}
+
+/// Buckets at `schema - 1`: bucket `i` merges into `ceil(i / 2)`.
+fn merge_halves(buckets: &BTreeMap<i32, u64>) -> BTreeMap<i32, u64> {
+    let mut merged = BTreeMap::new();
+    for (&index, &count) in buckets {
+        let index = (i64::from(index) + 1).div_euclid(2) as i32;
+        *merged.entry(index).or_default() += count;
+    }
+    merged
+}
+
+/// Recorder keeping the current value of every series. Cheap to clone.
+#[derive(Clone)]
+pub struct Store(Arc<Inner>);
+
+struct Inner {
+    registry: Registry<Key, GenerationalAtomicStorage>,
+    recency: Recency<Key>,
+    buckets: Vec<Buckets>,
+    native_histograms: bool,
+    distributions: Mutex<HashMap<Key, Distribution>>,
+}
+
+struct Distribution {
+    bounds: Vec<f64>,
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+    native: Option<Sparse>,
+}
+
+impl Distribution {
+    fn new(bounds: &[f64], native: bool) -> Self {
+        Self {
+            bounds: bounds.to_vec(),
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+            count: 0,
+            native: native.then(Sparse::new),
+        }
+    }
+
+    fn record(&mut self, values: &[f64]) {
+        for &value in values {
+            self.counts[self.bounds.partition_point(|&b| b < value)] += 1;
+            self.sum += value;
+            self.count += 1;
+            if let Some(native) = &mut self.native {
+                native.record(value);
+            }
+        }
+    }
+
+    fn value(&self) -> Value {
+        Value::Histogram {
+            bounds: self.bounds.clone(),
+            counts: self.counts.clone(),
+            sum: self.sum,
+            count: self.count,
+            native: self.native.clone(),
+        }
+    }
+}
+
+impl Store {
+    /// `native_histograms` also keeps sparse buckets for every histogram.
+    pub fn new(
+        gauge_idle_timeout: Option<Duration>,
+        buckets: &[Buckets],
+        native_histograms: bool,
+    ) -> Self {
+        Self(Arc::new(Inner {
+            registry: Registry::new(GenerationalAtomicStorage::atomic()),
+            recency: Recency::new(Clock::new(), MetricKindMask::GAUGE, gauge_idle_timeout),
+            buckets: buckets.to_vec(),
+            native_histograms,
+            distributions: Mutex::default(),
+        }))
+    }
+
+    /// Current value of every live series, sorted by name and labels.
+    pub fn snapshot(&self) -> Vec<Sample> {
+        let inner = &self.0;
+        let mut samples = Vec::new();
+        for (key, counter) in inner.registry.get_counter_handles() {
+            if inner
+                .recency
+                .should_store_counter(&key, counter.get_generation(), &inner.registry)
+            {
+                let value = counter.get_inner().load(Ordering::Acquire);
+                samples.push(Sample::new(&key, Value::Counter(value)));
+            }
+        }
+        for (key, gauge) in inner.registry.get_gauge_handles() {
+            if inner
+                .recency
+                .should_store_gauge(&key, gauge.get_generation(), &inner.registry)
+            {
+                let value = f64::from_bits(gauge.get_inner().load(Ordering::Acquire));
+                samples.push(Sample::new(&key, Value::Gauge(value)));
+            }
+        }
+        let mut distributions = inner.distributions.lock().unwrap();
+        for (key, histogram) in inner.registry.get_histogram_handles() {
+            if !inner.recency.should_store_histogram(
+                &key,
+                histogram.get_generation(),
+                &inner.registry,
+            ) {
+                distributions.remove(&key);
+                continue;
+            }
+            let distribution = distributions.entry(key.clone()).or_insert_with(|| {
+                let bounds = histogram_bounds(key.name(), &inner.buckets).unwrap_or_default();
+                Distribution::new(bounds, inner.native_histograms)
+            });
+            histogram
+                .get_inner()
+                .clear_with(|values| distribution.record(values));
+            samples.push(Sample::new(&key, distribution.value()));
+        }
+        samples.sort_by(|a, b| (&a.name, &a.labels).cmp(&(&b.name, &b.labels)));
+        samples
+    }
+}
+
+impl Sample {
+    fn new(key: &Key, value: Value) -> Self {
+        Self {
+            name: key.name().to_string(),
+            labels: key
+                .labels()
+                .map(|l| (l.key().to_string(), l.value().to_string()))
+                .collect(),
+            value,
+        }
+    }
+
+    pub fn label(&self, name: &str) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+impl Recorder for Store {
+    fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+    fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+    fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+    fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+        self.0
+            .registry
+            .get_or_create_counter(key, |c| c.clone().into())
+    }
+
+    fn register_gauge(&self, key: &Key, _: &Metadata<'_>) -> Gauge {
+        self.0
+            .registry
+            .get_or_create_gauge(key, |g| g.clone().into())
+    }
+
+    fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+        self.0
+            .registry
+            .get_or_create_histogram(key, |h| h.clone().into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_accumulates_histograms_across_calls() {
+        let store = Store::new(None, &[], false);
+        metrics::with_local_recorder(&store, || {
+            metrics::counter!("buildkit_builds_succeeded_total", "builder" => "b").increment(2);
+            metrics::gauge!("buildkit_workers_total", "builder" => "b").set(3.0);
+            let steps = metrics::histogram!("buildkit_build_steps", "builder" => "b");
+            steps.record(1.0);
+            steps.record(7.0);
+        });
+        let samples = store.snapshot();
+        let names: Vec<_> = samples.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "buildkit_build_steps",
+                "buildkit_builds_succeeded_total",
+                "buildkit_workers_total"
+            ]
+        );
+        assert_eq!(samples[1].value, Value::Counter(2));
+        assert_eq!(samples[2].value, Value::Gauge(3.0));
+        assert_eq!(samples[2].label("builder"), Some("b"));
+
+        metrics::with_local_recorder(&store, || {
+            metrics::histogram!("buildkit_build_steps", "builder" => "b").record(1000.0);
+        });
+        let Value::Histogram {
+            bounds,
+            counts,
+            sum,
+            count,
+            native: None,
+        } = &store.snapshot()[0].value
+        else {
+            panic!("not a histogram");
+        };
+        assert_eq!(bounds.len() + 1, counts.len());
+        assert_eq!(counts[0], 1);
+        assert_eq!(counts[2], 1);
+        assert_eq!(counts[bounds.len()], 1);
+        assert_eq!((*sum, *count), (1008.0, 3));
+    }
+
+    #[test]
+    fn native_buckets_follow_schema() {
+        let mut sparse = Sparse::new();
+        // Powers of two are bucket upper bounds: 2^k is bucket k * 2^schema.
+        for value in [1.0, 2.0, 2.1, 0.0, -1.0] {
+            sparse.record(value);
+        }
+        assert_eq!(sparse.zero_count, 1);
+        assert_eq!(
+            sparse.positive.iter().collect::<Vec<_>>(),
+            [(&0, &1), (&8, &1), (&9, &1)]
+        );
+        assert_eq!(sparse.negative.iter().collect::<Vec<_>>(), [(&0, &1)]);
+    }
+
+    #[test]
+    fn native_resolution_halves_past_bucket_limit() {
+        let mut sparse = Sparse::new();
+        for i in 0..=MAX_NATIVE_BUCKETS {
+            // Mid-bucket, so the bucket is `i`.
+            sparse.record(2f64.powf((i as f64 - 0.5) / 8.0));
+        }
+        assert_eq!(sparse.schema, NATIVE_SCHEMA - 1);
+        assert!(sparse.positive.len() <= MAX_NATIVE_BUCKETS);
+        assert_eq!(
+            sparse.positive.values().sum::<u64>(),
+            MAX_NATIVE_BUCKETS as u64 + 1
+        );
+        // Buckets 1 and 2 merge into bucket 1 at the coarser schema.
+        assert_eq!(sparse.positive[&1], 2);
+    }
+}
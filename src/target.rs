@@ -0,0 +1,153 @@
+//! A monitored BuildKit daemon: its connection, scrape loop and history subscription.
+//!
+//! Every daemon gets its own tasks and connection so one wedged builder does not
+//! delay the others, and all of its metrics carry a `builder` label.
+
+use crate::client::{Connection, DaemonAddr, TlsFiles};
+use crate::dedup::SeenRefs;
+use crate::generated::{
+    DiskUsageRequest, DiskUsageResponse, InfoRequest, InfoResponse, ListWorkersRequest,
+    ListWorkersResponse,
+};
+use crate::health::Health;
+use crate::history::{self, BuildSinks, RunningBuilds};
+use crate::labels::BuildLabels;
+use crate::metrics::{record_scrape, record_scrape_success, record_up};
+
+use anyhow::Result;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// Shared state of a running target, read by the HTTP endpoints.
+pub struct TargetState {
+    pub running: RunningBuilds,
+    pub health: Health,
+}
+
+#[derive(Debug)]
+pub struct Target {
+    /// Value of the `builder` label.
+    pub name: String,
+    pub addr: DaemonAddr,
+}
+
+impl Target {
+    /// Parse `[name=]address`. Without a name the address itself labels the builder.
+    pub fn parse(spec: &str, tls: &TlsFiles) -> Result<Self> {
+        let (name, addr) = match spec.split_once('=') {
+            Some((name, addr)) if !name.is_empty() && !name.contains([':', '/']) => (name, addr),
+            _ => (spec, spec),
+        };
+        Ok(Self {
+            name: name.to_string(),
+            addr: DaemonAddr::parse(addr, tls)?,
+        })
+    }
+
+    /// Start the connection supervisor, scrape loop and history subscription.
+    /// The returned task ends, with counted refs flushed, once `shutdown` turns true.
+    pub fn spawn(
+        self,
+        scrape_interval: Duration,
+        seen_refs: SeenRefs,
+        build_labels: Arc<BuildLabels>,
+        sinks: BuildSinks,
+        shutdown: watch::Receiver<bool>,
+    ) -> (TargetState, JoinHandle<()>) {
+        let running = RunningBuilds::new(&self.name);
+        let health = Health::default();
+
+        // One Control API connection shared by scraping and history, reconnected by its supervisor.
+        let conn = Connection::spawn(self.name.clone(), self.addr, health.clone());
+
+        // Background: periodically scrape BuildKit Control API and update metrics.
+        // Initial sleep gives the supervisor time to connect before the first attempt.
+        let conn_clone = conn.clone();
+        let running_clone = running.clone();
+        let health_clone = health.clone();
+        let name = self.name;
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            loop {
+                // Refresh in-flight ages even when the daemon is unreachable.
+                running_clone.record(SystemTime::now());
+                match scrape_once(&name, &conn_clone).await {
+                    Ok(()) => {
+                        let now = SystemTime::now();
+                        record_up(&name, true);
+                        record_scrape_success(&name, now);
+                        health_clone.record_scrape_success(now);
+                    }
+                    Err(e) => {
+                        tracing::warn!(builder = %name, err = %e, "scrape failed");
+                        record_up(&name, false);
+                        // Keep the timestamp series alive so staleness stays alertable.
+                        if let Some(at) = health_clone.last_scrape_success() {
+                            record_scrape_success(&name, at);
+                        }
+                        conn_clone.check(&e);
+                    }
+                }
+                tokio::time::sleep(scrape_interval).await;
+            }
+        });
+
+        // Background: tail build history so build counters update as builds complete.
+        let history = tokio::spawn(history::watch(
+            conn,
+            running.clone(),
+            seen_refs,
+            build_labels,
+            sinks,
+            shutdown,
+        ));
+
+        (TargetState { running, health }, history)
+    }
+}
+
+async fn scrape_once(builder: &str, conn: &Connection) -> Result<()> {
+    let mut client = conn.client()?;
+
+    let info: InfoResponse = conn
+        .observe("info", client.info(tonic::Request::new(InfoRequest {})))
+        .await?;
+    let workers: ListWorkersResponse = conn
+        .observe(
+            "list_workers",
+            client.list_workers(tonic::Request::new(ListWorkersRequest { filter: vec![] })),
+        )
+        .await?;
+    let disk: DiskUsageResponse = conn
+        .observe(
+            "disk_usage",
+            client.disk_usage(tonic::Request::new(DiskUsageRequest {
+                filter: vec![],
+                age_limit: 0,
+            })),
+        )
+        .await?;
+
+    record_scrape(builder, info, workers, disk, SystemTime::now());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_named_targets() {
+        let t = Target::parse("pool-0=tcp://10.0.0.1:1234", &TlsFiles::default()).unwrap();
+        assert_eq!(t.name, "pool-0");
+        assert_eq!(t.addr.to_string(), "tcp://10.0.0.1:1234");
+    }
+
+    #[test]
+    fn unnamed_targets_use_address() {
+        let t = Target::parse("unix:///run/buildkit/buildkitd.sock", &TlsFiles::default()).unwrap();
+        assert_eq!(t.name, "unix:///run/buildkit/buildkitd.sock");
+    }
+}
@@ -0,0 +1,362 @@
+//! Build traces: every newly completed build becomes an OTLP span, from
+//! `created_at` to `completed_at`, with its status taken from the build error.
+//! A build given a W3C `traceparent` (e.g. by the CI job that ran it) joins
+//! that trace; any other build starts a trace of its own.
+//!
+//! Builds whose `Status` stream the agent followed also get a child span per
+//! vertex (step) with its cached flag and error. Spans go through a
+//! [`SinkQueue`](crate::history::SinkQueue) and are sent in batches.
+
+use crate::generated::otlp::metrics::v1::{any_value, AnyValue, KeyValue};
+use crate::generated::otlp::trace::v1::span::SpanKind;
+use crate::generated::otlp::trace::v1::status::StatusCode;
+use crate::generated::otlp::trace::v1::{
+    ExportTraceServiceRequest, ResourceSpans, ScopeSpans, Span, Status,
+};
+use crate::generated::{BuildHistoryRecord, Vertex};
+use crate::history::SinkQueue;
+use crate::metrics::is_canceled;
+use crate::otlp::{key_value, scope, unix_nanos, Resources, Transport};
+
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+use tokio::sync::mpsc;
+
+/// Most builds sent in one export.
+const MAX_BATCH: usize = 128;
+
+/// Spans of one build, tagged with its builder.
+type BuildSpans = (String, Vec<Span>);
+
+/// Queues build spans for a background exporter. Cheap to clone.
+#[derive(Clone)]
+pub struct Tracer {
+    queue: SinkQueue<BuildSpans>,
+}
+
+impl Tracer {
+    /// Start the exporter task.
+    pub fn spawn(transport: Transport, resources: Resources) -> Self {
+        let (queue, rx) = SinkQueue::new();
+        tokio::spawn(export(rx, transport, resources));
+        Self { queue }
+    }
+
+    /// Queue the spans of a completed build. Builds without timestamps are skipped.
+    pub fn record(&self, builder: &str, record: &BuildHistoryRecord, vertexes: &[Vertex]) {
+        let Some(spans) = build_spans(record, vertexes) else {
+            return;
+        };
+        let spans = (builder.to_string(), spans);
+        self.queue.send("traces", builder, &record.r#ref, spans);
+    }
+}
+
+async fn export(mut rx: mpsc::Receiver<BuildSpans>, transport: Transport, resources: Resources) {
+    while let Some(first) = rx.recv().await {
+        let mut batch = vec![first];
+        while batch.len() < MAX_BATCH {
+            match rx.try_recv() {
+                Ok(build) => batch.push(build),
+                Err(_) => break,
+            }
+        }
+        if let Err(e) = transport.export_traces(request(&resources, batch)).await {
+            tracing::warn!(err = %e, "OTLP trace export failed");
+        }
+    }
+}
+
+fn request(resources: &Resources, batch: Vec<BuildSpans>) -> ExportTraceServiceRequest {
+    let mut builders: BTreeMap<String, Vec<Span>> = BTreeMap::new();
+    for (builder, spans) in batch {
+        builders.entry(builder).or_default().extend(spans);
+    }
+    let resource_spans = builders
+        .into_iter()
+        .map(|(builder, spans)| ResourceSpans {
+            resource: Some(resources.for_builder(Some(&builder))),
+            scope_spans: vec![ScopeSpans {
+                scope: Some(scope()),
+                spans,
+            }],
+        })
+        .collect();
+    ExportTraceServiceRequest { resource_spans }
+}
+
+/// Frontend attributes that may carry the trace context of whatever started
+/// the build: BuildKit keeps no trace ids in the history record itself, but
+/// build args (`--build-arg TRACEPARENT=...`) are recorded as frontend attrs.
+const TRACEPARENT_ATTRS: [&str; 2] = ["build-arg:TRACEPARENT", "traceparent"];
+
+/// Trace id and parent span id of a W3C `traceparent`
+/// (`00-<32 hex trace id>-<16 hex span id>-<2 hex flags>`).
+fn parse_traceparent(value: &str) -> Option<(Vec<u8>, Vec<u8>)> {
+    let mut parts = value.trim().split('-');
+    let (version, trace_id, span_id, flags) =
+        (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    if version != "00" || flags.len() != 2 || parts.next().is_some() {
+        return None;
+    }
+    let trace_id = hex(trace_id, 16)?;
+    let span_id = hex(span_id, 8)?;
+    // All-zero ids are invalid per the spec.
+    if trace_id.iter().all(|&b| b == 0) || span_id.iter().all(|&b| b == 0) {
+        return None;
+    }
+    Some((trace_id, span_id))
+}
+
+/// Decode exactly `len` bytes of lowercase hex.
+fn hex(s: &str, len: usize) -> Option<Vec<u8>> {
+    if s.len() != len * 2 || !s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return None;
+    }
+    (0..len)
+        .map(|i| u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok())
+        .collect()
+}
+
+/// The build's span followed by one child span per started vertex, in the
+/// trace of the build's `traceparent` if it has one, or else a fresh trace.
+fn build_spans(record: &BuildHistoryRecord, vertexes: &[Vertex]) -> Option<Vec<Span>> {
+    let start = timestamp(record.created_at)?;
+    let end = timestamp(record.completed_at)?;
+    let (trace_id, parent_span_id) = TRACEPARENT_ATTRS
+        .iter()
+        .find_map(|attr| parse_traceparent(record.frontend_attrs.get(*attr)?))
+        .unwrap_or_else(|| (rand::random::<[u8; 16]>().to_vec(), Vec::new()));
+    let span_id = rand::random::<[u8; 8]>().to_vec();
+
+    let error = record.error.as_ref().filter(|e| e.code != 0);
+    let status = match error {
+        // Canceled builds are not failures, so they keep the unset status.
+        Some(err) if is_canceled(err) => Status {
+            message: err.message.clone(),
+            code: StatusCode::Unset as i32,
+        },
+        Some(err) => Status {
+            message: err.message.clone(),
+            code: StatusCode::Error as i32,
+        },
+        None => Status {
+            message: String::new(),
+            code: StatusCode::Ok as i32,
+        },
+    };
+    let mut attributes = vec![
+        key_value("buildkit.ref", &record.r#ref),
+        key_value("buildkit.frontend", &record.frontend),
+        int_value("buildkit.steps.total", record.num_total_steps.into()),
+        int_value(
+            "buildkit.steps.completed",
+            record.num_completed_steps.into(),
+        ),
+        int_value("buildkit.steps.cached", record.num_cached_steps.into()),
+    ];
+    if let Some(err) = error {
+        attributes.push(int_value("buildkit.error.code", err.code.into()));
+        attributes.push(bool_value("buildkit.canceled", is_canceled(err)));
+    }
+    let mut spans = vec![Span {
+        trace_id: trace_id.clone(),
+        span_id: span_id.clone(),
+        parent_span_id,
+        name: "build".to_string(),
+        kind: SpanKind::Server as i32,
+        start_time_unix_nano: start,
+        end_time_unix_nano: end,
+        attributes,
+        status: Some(status),
+        ..Default::default()
+    }];
+
+    for vertex in vertexes {
+        let Some(started) = timestamp(vertex.started) else {
+            continue;
+        };
+        // Vertexes still running when the build ended (e.g. canceled) end with it.
+        let completed = timestamp(vertex.completed).unwrap_or(end);
+        let status = (!vertex.error.is_empty()).then(|| Status {
+            message: vertex.error.clone(),
+            code: StatusCode::Error as i32,
+        });
+        spans.push(Span {
+            trace_id: trace_id.clone(),
+            span_id: rand::random::<[u8; 8]>().to_vec(),
+            parent_span_id: span_id.clone(),
+            name: vertex.name.clone(),
+            kind: SpanKind::Internal as i32,
+            start_time_unix_nano: started,
+            end_time_unix_nano: completed.max(started),
+            attributes: vec![
+                key_value("buildkit.vertex.digest", &vertex.digest),
+                bool_value("buildkit.vertex.cached", vertex.cached),
+            ],
+            status,
+            ..Default::default()
+        });
+    }
+    Some(spans)
+}
+
+fn timestamp(t: Option<prost_types::Timestamp>) -> Option<u64> {
+    SystemTime::try_from(t?).ok().map(unix_nanos)
+}
+
+fn int_value(key: &str, value: i64) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: Some(AnyValue {
+            value: Some(any_value::Value::IntValue(value)),
+        }),
+    }
+}
+
+fn bool_value(key: &str, value: bool) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: Some(AnyValue {
+            value: Some(any_value::Value::BoolValue(value)),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generated::BuildError;
+
+    fn ts(seconds: i64) -> Option<prost_types::Timestamp> {
+        Some(prost_types::Timestamp { seconds, nanos: 0 })
+    }
+
+    fn vertex(name: &str, started: i64, completed: Option<i64>, cached: bool) -> Vertex {
+        Vertex {
+            digest: format!("sha256:{name}"),
+            name: name.to_string(),
+            cached,
+            started: ts(started),
+            completed: completed.and_then(ts),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn failed_build_has_error_status_and_vertex_children() {
+        let record = BuildHistoryRecord {
+            r#ref: "b1".into(),
+            frontend: "dockerfile.v0".into(),
+            created_at: ts(100),
+            completed_at: ts(160),
+            error: Some(BuildError {
+                code: 2,
+                message: "process \"/bin/sh -c make\" did not complete successfully".into(),
+            }),
+            ..Default::default()
+        };
+        let vertexes = [
+            vertex("[1/2] FROM alpine", 105, Some(105), true),
+            vertex("[2/2] RUN make", 106, None, false),
+            Vertex {
+                started: None,
+                ..vertex("[3/3] COPY", 0, None, false)
+            },
+        ];
+        let spans = build_spans(&record, &vertexes).unwrap();
+        assert_eq!(spans.len(), 3);
+
+        let build = &spans[0];
+        assert_eq!(build.name, "build");
+        assert_eq!(
+            (build.start_time_unix_nano, build.end_time_unix_nano),
+            (100_000_000_000, 160_000_000_000)
+        );
+        let status = build.status.as_ref().unwrap();
+        assert_eq!(status.code, StatusCode::Error as i32);
+        assert!(status.message.contains("did not complete"));
+
+        for child in &spans[1..] {
+            assert_eq!(child.trace_id, build.trace_id);
+            assert_eq!(child.parent_span_id, build.span_id);
+        }
+        assert_eq!(
+            spans[1].attributes[1],
+            bool_value("buildkit.vertex.cached", true)
+        );
+        // Unfinished vertexes end with the build.
+        assert_eq!(spans[2].end_time_unix_nano, 160_000_000_000);
+    }
+
+    #[test]
+    fn builds_without_timestamps_have_no_span() {
+        let record = BuildHistoryRecord {
+            created_at: ts(100),
+            ..Default::default()
+        };
+        assert!(build_spans(&record, &[]).is_none());
+    }
+
+    #[test]
+    fn canceled_and_zero_code_errors_are_not_failures() {
+        let status = |code, message: &str| {
+            let record = BuildHistoryRecord {
+                created_at: ts(100),
+                completed_at: ts(160),
+                error: Some(BuildError {
+                    code,
+                    message: message.into(),
+                }),
+                ..Default::default()
+            };
+            let spans = build_spans(&record, &[]).unwrap();
+            spans[0].status.as_ref().unwrap().code
+        };
+        assert_eq!(
+            status(tonic::Code::Cancelled as i32, "canceled"),
+            StatusCode::Unset as i32
+        );
+        assert_eq!(status(2, "context canceled"), StatusCode::Unset as i32);
+        assert_eq!(status(0, ""), StatusCode::Ok as i32);
+        assert_eq!(status(2, "exit code: 1"), StatusCode::Error as i32);
+    }
+
+    #[test]
+    fn traceparent_joins_the_caller_trace() {
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let mut record = BuildHistoryRecord {
+            created_at: ts(100),
+            completed_at: ts(160),
+            frontend_attrs: [("build-arg:TRACEPARENT".into(), traceparent.into())].into(),
+            ..Default::default()
+        };
+        let spans = build_spans(&record, &[vertex("RUN make", 101, Some(150), false)]).unwrap();
+        assert_eq!(
+            spans[0].trace_id,
+            [
+                0x4b, 0xf9, 0x2f, 0x35, 0x77, 0xb3, 0x4d, 0xa6, 0xa3, 0xce, 0x92, 0x9d, 0x0e, 0x0e,
+                0x47, 0x36
+            ]
+        );
+        assert_eq!(
+            spans[0].parent_span_id,
+            [0x00, 0xf0, 0x67, 0xaa, 0x0b, 0xa9, 0x02, 0xb7]
+        );
+        assert_eq!(spans[1].trace_id, spans[0].trace_id);
+        assert_eq!(spans[1].parent_span_id, spans[0].span_id);
+
+        // Malformed contexts fall back to a fresh root span.
+        for invalid in [
+            "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6-00f067aa0ba902b7-01",
+        ] {
+            record.frontend_attrs = [("traceparent".into(), invalid.into())].into();
+            let spans = build_spans(&record, &[]).unwrap();
+            assert_eq!(spans[0].trace_id.len(), 16);
+            assert!(spans[0].parent_span_id.is_empty(), "{invalid}");
+        }
+    }
+}
@@ -9,12 +9,48 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .build_server(false)
         .build_client(true)
         .out_dir(out_dir)
-        .compile_protos(&["proto/moby/buildkit/v1/control.proto"], &["proto"])?;
+        .compile_protos(
+            &[
+                "proto/moby/buildkit/v1/control.proto",
+                "proto/opentelemetry/proto/collector/metrics/v1/metrics_service.proto",
+                "proto/opentelemetry/proto/collector/trace/v1/trace_service.proto",
+                "proto/prometheus/remote.proto",
+                "proto/io/prometheus/client/metrics.proto",
+            ],
+            &["proto"],
+        )?;
 
-    // Tonic names the file from the package: moby.buildkit.v1 -> moby.buildkit.v1.rs
-    // Add mod.rs so the main crate can use `mod generated`.
+    // Tonic names each file from its package: moby.buildkit.v1 -> moby.buildkit.v1.rs
+    // Add mod.rs so the main crate can use `mod generated`; OTLP and remote-write types get
+    // their own modules since names like `Histogram` and `Sample` are generic.
     let mod_rs = r#"// Generated by `make generate`. Do not edit.
 include!("moby.buildkit.v1.rs");
+
+// Nested like the `opentelemetry.proto.collector.*` packages so the trace
+// service's references to shared metrics types resolve. prost keeps the proto
+// oneof names (`AnyValue.value.string_value`, ...).
+#[allow(clippy::enum_variant_names)]
+pub mod otlp {
+    pub mod metrics {
+        pub mod v1 {
+            include!("opentelemetry.proto.collector.metrics.v1.rs");
+        }
+    }
+    pub mod trace {
+        pub mod v1 {
+            include!("opentelemetry.proto.collector.trace.v1.rs");
+        }
+    }
+}
+
+pub mod prometheus {
+    include!("prometheus.rs");
+}
+
+// Prometheus client_model, for the protobuf exposition format.
+pub mod client_model {
+    include!("io.prometheus.client.rs");
+}
 "#;
     std::fs::write(std::path::Path::new(out_dir).join("mod.rs"), mod_rs)?;
 